@@ -3,10 +3,9 @@ use std::path::Path;
 use axum::http::{HeaderMap, HeaderValue};
 use reqwest::header::{CONTENT_TYPE, USER_AGENT};
 use serde_json::{Map, Value};
-use crate::{common::{get_addresses_url,is_valid_uk_postcode, read_lines}, extractors::extract_display_strings_from_value_map, store::redis_set_addresses_checked};
+use crate::{common::{get_addresses_url,is_valid_uk_postcode, read_lines}, extractors::extract_display_strings_from_value_map, store::CacheStore, upstream::{Upstream, UpstreamClient}};
 use string_patterns::PatternFilter;
 use rand::prelude::*;
-use crate::store::{redis_get_strings, redis_set_strings};
 
 const DEFAULT_SPIDER_USER_AGENT_STRING: &'static str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/117.0.0.0 Safari/537.36";
 
@@ -19,16 +18,16 @@ fn match_user_agent_file_name() -> Option<String> {
   None
 }
 
-fn get_user_agent_lines() -> Vec<String> {
+async fn get_user_agent_lines(store: &dyn CacheStore) -> Vec<String> {
   let ck = "user_agents";
-  let lines_opt = redis_get_strings(ck);
+  let lines_opt = store.get_strings(ck).await;
   if let Some(lines) = lines_opt {
     lines
   } else {
     if let Some(user_agent_list_file) = match_user_agent_file_name() {
       let text_lines = read_lines(&user_agent_list_file);
       if text_lines.len() > 5 {
-        redis_set_strings(ck, &text_lines);
+        store.set_strings(ck, &text_lines).await;
       }
       text_lines
     } else {
@@ -37,9 +36,9 @@ fn get_user_agent_lines() -> Vec<String> {
   }
 }
 
-fn get_random_ua_string() -> String {
+async fn get_random_ua_string(store: &dyn CacheStore) -> String {
   let mut ua_str= DEFAULT_SPIDER_USER_AGENT_STRING.to_string();
-  let lines = get_user_agent_lines();
+  let lines = get_user_agent_lines(store).await;
   let num_lines = lines.len();
   if num_lines > 1 {
     let random: usize = rand::thread_rng().gen();
@@ -51,16 +50,17 @@ fn get_random_ua_string() -> String {
   ua_str
 }
 
-fn build_headers() -> HeaderMap {
+async fn build_headers(store: &dyn CacheStore) -> HeaderMap {
   let mut hm = HeaderMap::new();
-  let ua = get_random_ua_string();
-  hm.insert(USER_AGENT, ua.parse().unwrap());
+  let ua = get_random_ua_string(store).await;
+  if let Ok(ua_value) = ua.parse() {
+    hm.insert(USER_AGENT, ua_value);
+  }
   hm.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
   hm
 }
 
-pub async fn get_remote_addresses(pc: &str) -> Option<Vec<String>> {
-  let client = reqwest::Client::new();
+pub async fn get_remote_addresses(store: &dyn CacheStore, upstream: &UpstreamClient, pc: &str) -> Option<Vec<String>> {
   let pc_code = pc.trim().to_uppercase();
   let valid = is_valid_uk_postcode(&pc_code);
   if valid {
@@ -68,21 +68,17 @@ pub async fn get_remote_addresses(pc: &str) -> Option<Vec<String>> {
     map.insert("Query", pc_code.clone());
     map.insert("CountryIsoCode", "GBR".to_string());
     let uri = get_addresses_url();
-    let hm = build_headers();
-    let result = client.post(&uri)
-    .headers(hm)
-      .json(&map)
-      .send()
-      .await;
-    if let Ok(resp) = result {
-      if let Ok(data) = resp.json::<Map<String, Value>>().await {
-        if data.contains_key("Data") {
-          redis_set_addresses_checked(pc);
-          let addresses = extract_display_strings_from_value_map(&data, "Data");
-          let pc_pat = format!(r#"\b{}"#, pc_code.replace(" ", r#"\s+"#));
-          let filtered_address = addresses.pattern_filter_ci(&pc_pat);
-          return Some(filtered_address);
-        }
+    let hm = build_headers(store).await;
+    let request = upstream.post(Upstream::Addresses, &uri)
+      .headers(hm)
+      .json(&map);
+    if let Ok(data) = upstream.send_json::<Map<String, Value>>(request, Upstream::Addresses).await {
+      if data.contains_key("Data") {
+        store.set_addresses_checked(pc).await;
+        let addresses = extract_display_strings_from_value_map(&data, "Data");
+        let pc_pat = format!(r#"\b{}"#, pc_code.replace(" ", r#"\s+"#));
+        let filtered_address = addresses.pattern_filter_ci(&pc_pat);
+        return Some(filtered_address);
       }
     }
   }
@@ -1,8 +1,8 @@
+use chrono::{DateTime, Utc};
 use serde_json::{Map, Value};
-use crate::{common::get_astro_url, models::{AstroData, Geo}, simple_iso::timestamp_from_string, store::{redis_get_astro_data, redis_set_astro_data}};
+use crate::{common::get_astro_url, errors::{GeoFinderError, GeoFinderResult}, models::{AstroData, AstroDay, Geo}, simple_iso::timestamp_from_string, store::CacheStore, timezones::resolve_local_time, upstream::{Upstream, UpstreamClient}};
 
-async fn fetch_core_astro(geo: Geo, ts_opt: Option<i64>) -> Option<Map<String, Value>> {
-  let req_client = reqwest::Client::new();
+async fn fetch_core_astro(upstream: &UpstreamClient, geo: Geo, ts_opt: Option<i64>) -> GeoFinderResult<Map<String, Value>> {
   let loc = geo.to_string();
   let mut query_params = vec![
     ("loc", loc.as_str()),
@@ -16,54 +16,123 @@ async fn fetch_core_astro(geo: Geo, ts_opt: Option<i64>) -> Option<Map<String, V
     query_params.push(("jd", &jd_string));
   }
   let uri = format!("{}/{}", get_astro_url(), "ascendant");
-  let result = req_client.get(&uri)
-    .query(&query_params).send()
-    .await
-    .expect("failed to get response")
-    .text()
-    .await;
-  if let Ok(result_string) = result {
-    if let Ok(json) = serde_json::from_str(&result_string) {
-      return Some(json);
-    }
-  }
-  None
+  let request = upstream.get(Upstream::Astro, &uri).query(&query_params);
+  upstream.send_json(request, Upstream::Astro).await
 }
 
-pub async fn get_astro_data(geo: Geo, ts_opt: Option<i64>) -> Option<AstroData> {
-  let data_opt = fetch_core_astro(geo, ts_opt).await;
-  if let Some(data) = data_opt {
-    if data.contains_key("date") && data.contains_key("values") {
-      let astro = AstroData::new(&data);
-      return  Some(astro);
-    }
+pub async fn get_astro_data(upstream: &UpstreamClient, geo: Geo, ts_opt: Option<i64>) -> GeoFinderResult<AstroData> {
+  let data = fetch_core_astro(upstream, geo, ts_opt).await?;
+  if data.contains_key("date") && data.contains_key("values") {
+    let astro = AstroData::new(&data);
+    return Ok(astro);
   }
-  None
+  Err(GeoFinderError::UpstreamMalformed(Upstream::Astro.name().to_string()))
 }
 
-pub async fn get_astro_data_cached(geo: Geo, dt_opt: Option<String>) -> Option<AstroData> {
+/// Astro data from the remote service, cached per half hour. In local mode, or when the remote
+/// service is unavailable, sun and moon data are calculated in-process and not cached.
+pub async fn get_astro_data_cached(store: &dyn CacheStore, upstream: &UpstreamClient, geo: Geo, dt_opt: Option<String>, local: bool) -> GeoFinderResult<AstroData> {
   let mut ts_opt: Option<i64> = None;
   if let Some(dt) = dt_opt.clone() {
     ts_opt = timestamp_from_string(&dt);
   }
+  if local {
+    return Ok(AstroData::new_local(geo, ts_opt.unwrap_or(Utc::now().timestamp())));
+  }
   let ts_key = if ts_opt.is_some() {
     (ts_opt.unwrap_or(0) / 1800).to_string()
   } else {
     "c".to_owned()
   };
   let key = format!("astro_data_{}_{}", geo.to_approx_key(2), ts_key);
-  let mut astro_opt = redis_get_astro_data(&key);
-  let is_cached = astro_opt.is_some();
-  if !is_cached {
-    astro_opt = get_astro_data(geo, ts_opt).await; 
+  if let Some(mut astro) = store.get_astro_data(&key).await {
+    astro.set_age();
+    return Ok(astro);
   }
-  if let Some(mut astro) = astro_opt {
-    if is_cached {
-      astro.set_age();
-    } else {
-      redis_set_astro_data(&key, &astro);
-    }
-    return Some(astro);
+  match get_astro_data(upstream, geo, ts_opt).await {
+    Ok(astro) => {
+      store.set_astro_data(&key, &astro).await;
+      Ok(astro)
+    },
+    Err(error) if error.is_upstream() => {
+      tracing::warn!("{}, calculating sun and moon data locally", error);
+      Ok(AstroData::new_local(geo, ts_opt.unwrap_or(Utc::now().timestamp())))
+    },
+    Err(error) => Err(error)
   }
-  None
-}
\ No newline at end of file
+}
+
+/// Upper bound on days in one astro calendar
+pub const MAX_CALENDAR_DAYS: i64 = 366;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// Day-by-day sun and moon events from the built-in calculators for local calendar days in a zone.
+/// `start_date` is local midnight of the first day as seconds since the epoch read as UTC.
+/// Each day is cached on its own so overlapping ranges reuse earlier results. Cached days are
+/// read and written in one batch each and missing days are calculated on the blocking pool.
+pub async fn get_astro_calendar_cached(store: &dyn CacheStore, geo: Geo, zone_name: &str, start_date: i64, num_days: i64) -> GeoFinderResult<Vec<AstroDay>> {
+  let dates: Vec<(i64, String)> = (0..num_days).map(|index| {
+    let local_ts = start_date + index * DAY_SECS;
+    let date = DateTime::from_timestamp(local_ts, 0).map(|dt| dt.format("%Y-%m-%d").to_string()).unwrap_or_default();
+    (local_ts, date)
+  }).collect();
+  let keys: Vec<String> = dates.iter()
+    .map(|(_, date)| format!("astro_day_{}_{}_{}", geo.to_approx_key(2), zone_name, date))
+    .collect();
+  let cached = store.get_astro_days(&keys).await;
+  let missing: Vec<(usize, i64, String)> = cached.iter().enumerate()
+    .filter(|(_, day)| day.is_none())
+    .map(|(index, _)| (index, dates[index].0, dates[index].1.clone()))
+    .collect();
+  if missing.is_empty() {
+    return Ok(cached.into_iter().flatten().collect());
+  }
+  let zone = zone_name.to_string();
+  let calculated = tokio::task::spawn_blocking(move || calc_astro_days(geo, &zone, missing))
+    .await
+    .map_err(|e| {
+      tracing::error!("astro calendar calculation failed: {}", e);
+      GeoFinderError::StorageFailure("the calendar calculation failed".to_string())
+    })??;
+  let new_entries: Vec<(String, AstroDay)> = calculated.iter()
+    .map(|(index, day)| (keys[*index].clone(), day.clone()))
+    .collect();
+  store.set_astro_days(&new_entries).await;
+  let mut days = cached;
+  for (index, day) in calculated {
+    days[index] = Some(day);
+  }
+  Ok(days.into_iter().flatten().collect())
+}
+
+/// Calculates each (index, local midnight, date) day, keeping its index in the full range
+fn calc_astro_days(geo: Geo, zone_name: &str, dates: Vec<(usize, i64, String)>) -> GeoFinderResult<Vec<(usize, AstroDay)>> {
+  let to_utc = |ts: i64| resolve_local_time(zone_name, ts).map(|matched| matched.utc_ts())
+    .ok_or(GeoFinderError::BadInput(format!("{} is not a known timezone", zone_name)));
+  dates.into_iter().map(|(index, local_ts, date)| {
+    let day = AstroDay::new(&date, geo, to_utc(local_ts)?, to_utc(local_ts + DAY_SECS)?, to_utc(local_ts + DAY_SECS / 2)?);
+    Ok((index, day))
+  }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::MemoryStore;
+
+  #[tokio::test]
+  async fn calendar_fills_only_uncached_days_in_date_order() {
+    let store: &dyn CacheStore = &MemoryStore::new();
+    let geo = Geo::new(51.5074, -0.1278, 0.0);
+    // 2024-03-30, the day before the clocks change in London
+    let start = 1_711_756_800;
+    let first = get_astro_calendar_cached(store, geo, "Europe/London", start + DAY_SECS, 2).await.unwrap();
+    let days = get_astro_calendar_cached(store, geo, "Europe/London", start, 4).await.unwrap();
+    let dates: Vec<&str> = days.iter().map(|day| day.date.as_str()).collect();
+    assert_eq!(dates, vec!["2024-03-30", "2024-03-31", "2024-04-01", "2024-04-02"]);
+    assert_eq!(days[1].start, first[0].start);
+    // the local day the clocks go forward lasts 23 hours
+    assert_eq!(days[1].end - days[1].start, 23 * 60 * 60);
+  }
+}
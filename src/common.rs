@@ -3,8 +3,9 @@ use axum::{http::StatusCode, response::IntoResponse};
 use serde::Deserialize;
 use serde_with::skip_serializing_none;
 use simple_string_patterns::*;
-use string_patterns::PatternMatch;
-use crate::models::Geo;
+use string_patterns::{PatternMatch, PatternReplace};
+use crate::errors::{GeoFinderError, GeoFinderResult};
+use crate::models::{BoundingBox, Geo, PcMatchType};
 use crate::simple_iso::*;
 
 pub fn get_db_name() -> String {
@@ -27,6 +28,60 @@ pub fn get_astro_url() -> String {
   dotenv::var("ASTRO_API").unwrap_or("http://localhost:8080".to_string())
 }
 
+/// Maximum number of concurrent GeoTimeZone lookups per batch request
+pub fn get_batch_concurrency() -> usize {
+  dotenv::var("BATCH_CONCURRENCY").ok().and_then(|v| v.parse::<usize>().ok()).filter(|v| *v > 0).unwrap_or(4)
+}
+
+/// Name of the 2dsphere-indexed GeoJSON point field in the zones collection
+pub fn get_zones_geo_field() -> String {
+  dotenv::var("ZONES_GEO_FIELD").unwrap_or("geo".to_string())
+}
+
+/// Deadline for each section of a composite location response
+pub fn get_section_deadline_ms() -> u64 {
+  dotenv::var("SECTION_DEADLINE_MS").ok().and_then(|v| v.parse::<u64>().ok()).filter(|v| *v > 0).unwrap_or(4000)
+}
+
+/// Per-request timeout for an upstream service, e.g. GEONAMES_TIMEOUT_SECS
+pub fn get_upstream_timeout_secs(service_key: &str, default: u64) -> u64 {
+  dotenv::var(format!("{}_TIMEOUT_SECS", service_key)).ok().and_then(|v| v.parse::<u64>().ok()).filter(|v| *v > 0).unwrap_or(default)
+}
+
+/// Maximum concurrent requests to an upstream service, e.g. GEONAMES_MAX_CONCURRENCY
+pub fn get_upstream_concurrency(service_key: &str, default: usize) -> usize {
+  dotenv::var(format!("{}_MAX_CONCURRENCY", service_key)).ok().and_then(|v| v.parse::<usize>().ok()).filter(|v| *v > 0).unwrap_or(default)
+}
+
+/// Retries after the first attempt for idempotent upstream GET requests
+pub fn get_upstream_max_retries() -> u32 {
+  dotenv::var("UPSTREAM_MAX_RETRIES").ok().and_then(|v| v.parse::<u32>().ok()).unwrap_or(2)
+}
+
+/// Consecutive failed requests after which an upstream circuit breaker opens
+pub fn get_breaker_threshold() -> u32 {
+  dotenv::var("UPSTREAM_BREAKER_THRESHOLD").ok().and_then(|v| v.parse::<u32>().ok()).filter(|v| *v > 0).unwrap_or(5)
+}
+
+/// Seconds an open circuit breaker fails fast before letting a trial request through
+pub fn get_breaker_cooldown_secs() -> u64 {
+  dotenv::var("UPSTREAM_BREAKER_COOLDOWN_SECS").ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(30)
+}
+
+/// GeoJSON timezone boundaries for resolving timezones locally, e.g. timezone-boundary-builder's combined.json
+pub fn get_tz_boundaries_file() -> Option<String> {
+  dotenv::var("TZ_BOUNDARIES_FILE").ok().filter(|path| !path.trim().is_empty())
+}
+
+/// GeoNames citiesNNNN.txt or allCountries.txt dump for resolving nearby places locally
+pub fn get_gazetteer_file() -> Option<String> {
+  dotenv::var("GAZETTEER_FILE").ok().filter(|path| !path.trim().is_empty())
+}
+
+pub fn get_cache_backend() -> String {
+  dotenv::var("CACHE_BACKEND").unwrap_or("redis".to_string()).to_lowercase()
+}
+
 pub async fn handler_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "nothing to see here")
 }
@@ -74,27 +129,72 @@ pub struct GeoParams {
   pub cc: Option<String>,
   pub zn: Option<String>,
   pub astro: Option<u8>,
+  pub bbox: Option<String>,
+  pub format: Option<String>,
+  pub start: Option<String>,
+  pub end: Option<String>,
+  pub local: Option<u8>,
+  pub near: Option<String>,
+}
+
+/// Parses lat,lng or lat,lng,alt
+fn geo_from_loc(loc_opt: &Option<String>) -> Option<Geo> {
+  let nums = if let Some(loc_str) = loc_opt.clone() {
+    loc_str.split_to_numbers::<f64>(",")
+  } else {
+    vec![]
+  };
+  if nums.len() > 1 {
+    let lat = nums.get(0).unwrap_or(&0.0).to_owned();
+    let lng = nums.get(1).unwrap_or(&0.0).to_owned();
+    if nums.len() == 2 {
+      Some(Geo::simple(lat, lng))
+    } else {
+      let alt = nums.get(2).unwrap_or(&0.0).to_owned();
+      Some(Geo::new(lat, lng, alt))
+    }
+  } else {
+    None
+  }
 }
 
 impl GeoParams {
   pub fn to_geo_opt(&self) -> Option<Geo> {
-    let nums = if let Some(loc_str) = self.loc.clone() {
-      loc_str.split_to_numbers::<f64>(",")
+    geo_from_loc(&self.loc)
+  }
+
+  pub fn wants_geojson(&self) -> bool {
+    is_geojson_format(&self.format)
+  }
+
+  /// Bias point for ranking place lookups as lat,lng
+  pub fn to_near_opt(&self) -> Option<Geo> {
+    geo_from_loc(&self.near)
+  }
+
+  /// local=1 computes astronomical data in-process instead of calling the Astro API
+  pub fn is_local(&self) -> bool {
+    self.local.unwrap_or(0) > 0
+  }
+
+  pub fn require_geo(&self) -> GeoFinderResult<Geo> {
+    self.to_geo_opt().ok_or(GeoFinderError::BadInput("loc must be a comma-separated latitude and longitude".to_string()))
+  }
+
+  /// bbox is parsed as minLat,minLng,maxLat,maxLng, matching the lat,lng order of loc
+  pub fn require_bbox(&self) -> GeoFinderResult<BoundingBox> {
+    let nums = if let Some(bbox_str) = self.bbox.clone() {
+      bbox_str.split_to_numbers::<f64>(",")
     } else {
       vec![]
     };
-    if nums.len() > 1 {
-      let lat = nums.get(0).unwrap_or(&0.0).to_owned();
-      let lng = nums.get(1).unwrap_or(&0.0).to_owned();
-      if nums.len() == 2 {
-        Some(Geo::simple(lat, lng))
-      } else {
-        let alt = nums.get(2).unwrap_or(&0.0).to_owned();
-        Some(Geo::new(lat, lng, alt))
+    if nums.len() == 4 {
+      let bbox = BoundingBox::new(nums[0], nums[1], nums[2], nums[3]);
+      if bbox.is_valid() {
+        return Ok(bbox);
       }
-    } else {
-      None
     }
+    Err(GeoFinderError::BadInput("bbox must be minLat,minLng,maxLat,maxLng".to_string()))
   }
 }
 
@@ -108,12 +208,206 @@ pub struct PostParams {
   pub skip: Option<u32>,
   pub limit: Option<u32>,
   pub code: Option<String>,
+  pub format: Option<String>,
+  pub include: Option<String>,
+  pub exclude: Option<String>,
 }
 
 impl PostParams {
   pub fn has_geo(&self) -> bool {
     self.lat.is_some() && self.lng.is_some()
   }
+
+  pub fn wants_geojson(&self) -> bool {
+    is_geojson_format(&self.format)
+  }
+
+  /// Sections of a composite location response, from comma-separated `include` and `exclude` lists.
+  /// All sections are included by default and exclusions are applied after inclusions.
+  pub fn to_sections(&self) -> GeoFinderResult<LocationSections> {
+    let mut sections = match self.include.as_deref() {
+      Some(names) => LocationSections::none().with_names(names, true)?,
+      None => LocationSections::default()
+    };
+    if let Some(names) = self.exclude.as_deref() {
+      sections = sections.with_names(names, false)?;
+    }
+    Ok(sections)
+  }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct LocationSections {
+  pub zones: bool,
+  pub places: bool,
+  pub states: bool,
+  pub weather: bool,
+  pub poi: bool,
+  pub wikipedia: bool,
+}
+
+impl Default for LocationSections {
+  fn default() -> Self {
+    LocationSections {
+      zones: true,
+      places: true,
+      states: true,
+      weather: true,
+      poi: true,
+      wikipedia: true
+    }
+  }
+}
+
+impl LocationSections {
+  pub fn none() -> Self {
+    LocationSections {
+      zones: false,
+      places: false,
+      states: false,
+      weather: false,
+      poi: false,
+      wikipedia: false
+    }
+  }
+
+  fn with_names(mut self, names: &str, enabled: bool) -> GeoFinderResult<Self> {
+    for name in names.split(',').map(|n| n.trim().to_lowercase()).filter(|n| !n.is_empty()) {
+      match name.as_str() {
+        "zones" | "zone" | "surrounding" => self.zones = enabled,
+        "places" => self.places = enabled,
+        "states" => self.states = enabled,
+        "weather" => self.weather = enabled,
+        "poi" => self.poi = enabled,
+        "wikipedia" | "wiki" => self.wikipedia = enabled,
+        _ => return Err(GeoFinderError::BadInput(format!("unknown section {}, expected zones, places, states, weather, poi or wikipedia", name)))
+      }
+    }
+    Ok(self)
+  }
+
+  /// Postcode zones need the nearby place to tell UK from other locations
+  pub fn needs_place(&self) -> bool {
+    self.zones || self.places || self.states
+  }
+}
+
+fn is_geojson_format(format: &Option<String>) -> bool {
+  format.as_deref().map(|f| f.eq_ignore_ascii_case("geojson")).unwrap_or(false)
+}
+
+pub const MAX_TIME_TARGETS: usize = 20;
+
+/// A zone name or lat,lng location for time conversion
+#[derive(Deserialize, Debug, Clone)]
+pub struct TimeLocation {
+  pub zn: Option<String>,
+  pub loc: Option<String>,
+}
+
+impl TimeLocation {
+  pub fn to_geo_opt(&self) -> Option<Geo> {
+    geo_from_loc(&self.loc)
+  }
+
+  pub fn validate(&self) -> GeoFinderResult<()> {
+    if self.zn.is_some() || self.to_geo_opt().is_some() {
+      Ok(())
+    } else {
+      Err(GeoFinderError::BadInput("each location requires a zone name (zn) or lat,lng (loc)".to_string()))
+    }
+  }
+}
+
+/// Local wall-clock time at a source zone or location, converted to one or more targets
+#[derive(Deserialize, Debug, Clone)]
+pub struct TimeConvertParams {
+  #[serde(flatten)]
+  pub source: TimeLocation,
+  pub dt: String,
+  pub targets: Vec<TimeLocation>,
+}
+
+impl TimeConvertParams {
+  pub fn validate(&self) -> GeoFinderResult<()> {
+    self.source.validate()?;
+    if self.targets.is_empty() || self.targets.len() > MAX_TIME_TARGETS {
+      return Err(GeoFinderError::BadInput(format!("between 1 and {} targets are required", MAX_TIME_TARGETS)));
+    }
+    self.targets.iter().try_for_each(|target| target.validate())
+  }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GeoJsonPolygon {
+  #[serde(rename="type")]
+  pub geo_type: String,
+  pub coordinates: Vec<Vec<Vec<f64>>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PolygonParams {
+  pub polygon: GeoJsonPolygon,
+  pub skip: Option<u32>,
+  pub limit: Option<u32>,
+}
+
+impl PolygonParams {
+  /// Validates the polygon and closes any open rings
+  pub fn to_rings(&self) -> GeoFinderResult<Vec<Vec<Vec<f64>>>> {
+    if self.polygon.geo_type != "Polygon" || self.polygon.coordinates.is_empty() {
+      return Err(GeoFinderError::BadInput("polygon must be a GeoJSON Polygon".to_string()));
+    }
+    let mut rings = self.polygon.coordinates.clone();
+    for ring in rings.iter_mut() {
+      let valid_positions = ring.iter().all(|pos| pos.len() >= 2
+        && (-180.0..=180.0).contains(&pos[0]) && (-90.0..=90.0).contains(&pos[1]));
+      if !valid_positions {
+        return Err(GeoFinderError::BadInput("polygon positions must be [lng, lat] pairs".to_string()));
+      }
+      if ring.first() != ring.last() {
+        if let Some(first) = ring.first().cloned() {
+          ring.push(first);
+        }
+      }
+      if ring.len() < 4 {
+        return Err(GeoFinderError::BadInput("polygon rings need at least 3 distinct positions".to_string()));
+      }
+    }
+    Ok(rings)
+  }
+}
+
+pub const MAX_BATCH_POINTS: usize = 1000;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BatchPoint {
+  pub lat: f64,
+  pub lng: f64,
+  pub dt: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BatchParams {
+  pub points: Vec<BatchPoint>,
+}
+
+impl BatchParams {
+  /// Validated coordinates with any invalid date strings dropped
+  pub fn to_points(&self) -> GeoFinderResult<Vec<(Geo, Option<String>)>> {
+    if self.points.is_empty() || self.points.len() > MAX_BATCH_POINTS {
+      return Err(GeoFinderError::BadInput(format!("between 1 and {} points are required", MAX_BATCH_POINTS)));
+    }
+    let mut points: Vec<(Geo, Option<String>)> = Vec::with_capacity(self.points.len());
+    for (index, point) in self.points.iter().enumerate() {
+      if !(-90.0..=90.0).contains(&point.lat) || !(-180.0..=180.0).contains(&point.lng) {
+        return Err(GeoFinderError::BadInput(format!("point {} is out of range", index)));
+      }
+      let dt_opt = point.dt.clone().filter(|ds| is_valid_date_string(ds));
+      points.push((Geo::simple(point.lat, point.lng), dt_opt));
+    }
+    Ok(points)
+  }
 }
 
 pub fn is_valid_date_string(dt_str: &str) -> bool {
@@ -128,6 +422,45 @@ pub fn is_valid_uk_postcode(dt_str: &str) -> bool {
   dt_str.pattern_match_cs(r#"^[A-Z]+\d+[A-Z]?\s+\d"#)
 }
 
+/// Normalises a full postcode, sector ("SW1A 1") or outward code ("SW1A") to upper case
+/// with a single space between the outward and inward parts.
+/// Full postcodes may be entered without the space.
+pub fn parse_uk_postcode(pc_str: &str) -> Option<(String, PcMatchType)> {
+  let pc = pc_str.trim().to_uppercase().pattern_replace_cs(r#"\s+"#, " ");
+  let outward = r#"[A-Z]{1,2}\d[A-Z\d]?"#;
+  if pc.pattern_match_cs(&format!(r#"^{} ?\d[A-Z]{{2}}$"#, outward)) {
+    let compact = pc.replace(" ", "");
+    let (out_code, in_code) = compact.split_at(compact.len() - 3);
+    Some((format!("{} {}", out_code, in_code), PcMatchType::Full))
+  } else if pc.pattern_match_cs(&format!(r#"^{} \d$"#, outward)) {
+    Some((pc, PcMatchType::Sector))
+  } else if pc.pattern_match_cs(&format!(r#"^{}$"#, outward)) {
+    Some((pc, PcMatchType::Outcode))
+  } else {
+    None
+  }
+}
+
+/// Normalises a typed postcode prefix as `match_pc_zone` does, keeping a single trailing
+/// space so "W1 " only matches the W1 district
+pub fn normalize_pc_prefix(prefix: &str) -> Option<String> {
+  let pc = prefix.trim_start().to_uppercase().pattern_replace_cs(r#"\s+"#, " ");
+  if pc.pattern_match_cs(r#"^[A-Z][A-Z\d ]*$"#) && pc.len() <= 8 {
+    Some(pc)
+  } else {
+    None
+  }
+}
+
+/// Anchored regex for a normalised prefix. A compact prefix such as "SW1A1" may include
+/// the start of the inward code, so an optional space is allowed before its digit.
+pub fn pc_prefix_regex(prefix: &str) -> String {
+  if prefix.contains(' ') {
+    return format!("^{}", prefix);
+  }
+  format!("^{}", prefix.to_string().pattern_replace_cs(r#"^([A-Z]{1,2}\d[A-Z\d]?)(\d[A-Z]{0,2})$"#, "$1 ?$2"))
+}
+
 pub fn natural_tz_offset_from_utc(lng: f64) -> i64 {
   let lng360 = (lng + 540f64) % 360f64;
   let lng180 = lng360 - 180f64;
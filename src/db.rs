@@ -1,5 +1,6 @@
 use std::time::Duration;
 use mongodb::options::Compressor;
+use redis::{ConnectionInfo, IntoConnectionInfo, RedisResult};
 
 pub struct DatabaseConfig {
     pub uri: String,
@@ -46,3 +47,42 @@ impl DatabaseConfig {
         }
     }
 }
+
+pub struct CacheConfig {
+    pub uri: String,
+    pub db: Option<i64>,
+    pub password: Option<String>
+}
+
+impl CacheConfig {
+    pub fn new() -> Self {
+        let redis_uri: String = std::env::var("REDIS_URL")
+            .unwrap_or("redis://127.0.0.1/".to_string());
+
+        let redis_db: Option<i64> = std::env::var("REDIS_DB")
+            .ok()
+            .map(|db| db.parse().expect("Failed to parse `REDIS_DB` environment variable."));
+
+        let redis_password: Option<String> = std::env::var("REDIS_PASSWORD")
+            .ok()
+            .filter(|pw| !pw.is_empty());
+
+        Self {
+            uri: redis_uri,
+            db: redis_db,
+            password: redis_password
+        }
+    }
+
+    /// REDIS_DB and REDIS_PASSWORD override any database index or password in the URL
+    pub fn to_connection_info(&self) -> RedisResult<ConnectionInfo> {
+        let mut info = self.uri.as_str().into_connection_info()?;
+        if let Some(db) = self.db {
+            info.redis.db = db;
+        }
+        if let Some(password) = self.password.clone() {
+            info.redis.password = Some(password);
+        }
+        Ok(info)
+    }
+}
@@ -0,0 +1,99 @@
+use std::fmt;
+use axum::{http::StatusCode, response::{IntoResponse, Response}, Json};
+use serde_json::json;
+
+#[derive(Debug, Clone)]
+pub enum GeoFinderError {
+  BadInput(String),
+  UpstreamTimeout(String),
+  UpstreamUnavailable(String),
+  UpstreamMalformed(String),
+  NotFound(String),
+  StorageFailure(String),
+}
+
+pub type GeoFinderResult<T> = Result<T, GeoFinderError>;
+
+impl GeoFinderError {
+  /// Machine-readable error code included in every error response
+  pub fn code(&self) -> &'static str {
+    match self {
+      Self::BadInput(_) => "bad_input",
+      Self::UpstreamTimeout(_) => "upstream_timeout",
+      Self::UpstreamUnavailable(_) => "upstream_unavailable",
+      Self::UpstreamMalformed(_) => "upstream_malformed",
+      Self::NotFound(_) => "not_found",
+      Self::StorageFailure(_) => "storage_failure",
+    }
+  }
+
+  pub fn status(&self) -> StatusCode {
+    match self {
+      Self::BadInput(_) => StatusCode::BAD_REQUEST,
+      Self::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+      Self::UpstreamUnavailable(_) | Self::UpstreamMalformed(_) => StatusCode::BAD_GATEWAY,
+      Self::NotFound(_) => StatusCode::NOT_FOUND,
+      Self::StorageFailure(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+  }
+
+  pub fn message(&self) -> String {
+    match self {
+      Self::BadInput(msg) => msg.to_owned(),
+      Self::UpstreamTimeout(service) => format!("{} timed out", service),
+      Self::UpstreamUnavailable(service) => format!("{} is unavailable", service),
+      Self::UpstreamMalformed(service) => format!("{} returned an unexpected response", service),
+      Self::NotFound(msg) => msg.to_owned(),
+      Self::StorageFailure(msg) => msg.to_owned(),
+    }
+  }
+
+  /// Maps a reqwest failure from the named upstream service
+  pub fn from_upstream(service: &str, error: reqwest::Error) -> Self {
+    if error.is_timeout() {
+      Self::UpstreamTimeout(service.to_string())
+    } else if error.is_decode() {
+      Self::UpstreamMalformed(service.to_string())
+    } else {
+      Self::UpstreamUnavailable(service.to_string())
+    }
+  }
+
+  /// Name of the failing upstream service for upstream errors
+  pub fn service(&self) -> Option<&str> {
+    match self {
+      Self::UpstreamTimeout(service) | Self::UpstreamUnavailable(service) | Self::UpstreamMalformed(service) => Some(service),
+      _ => None
+    }
+  }
+
+  pub fn is_upstream(&self) -> bool {
+    matches!(self, Self::UpstreamTimeout(_) | Self::UpstreamUnavailable(_) | Self::UpstreamMalformed(_))
+  }
+}
+
+impl fmt::Display for GeoFinderError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {}", self.code(), self.message())
+  }
+}
+
+impl std::error::Error for GeoFinderError {}
+
+/// Driver errors can name hosts and credentials, so only a generic message reaches the client
+impl From<mongodb::error::Error> for GeoFinderError {
+  fn from(error: mongodb::error::Error) -> Self {
+    tracing::error!("mongodb error: {}", error);
+    Self::StorageFailure("the database is unavailable".to_string())
+  }
+}
+
+impl IntoResponse for GeoFinderError {
+  fn into_response(self) -> Response {
+    if self.is_upstream() {
+      tracing::warn!("{}", self);
+    }
+    let body = json!({ "valid": false, "code": self.code(), "message": self.message() });
+    (self.status(), Json(body)).into_response()
+  }
+}
@@ -1,10 +1,10 @@
 use mongodb::{
-    bson::{doc, Document}, options::{AggregateOptions, FindOptions}, Client, Collection
+    bson::{doc, Bson, Document}, options::{AggregateOptions, FindOptions}, Client, Collection
 };
 use futures::stream::StreamExt;
 use string_patterns::*;
 
-use crate::{common::{build_store_key_from_geo, get_db_name}, models::{Geo, PcInfo, PcRow, PcZone}, store::{redis_get_pc_results, redis_get_postcode, redis_set_pc_results, redis_set_postcode}};
+use crate::{common::{build_store_key_from_geo, get_db_name, get_zones_geo_field, parse_uk_postcode, pc_prefix_regex}, errors::{GeoFinderError, GeoFinderResult}, models::{Geo, PcInfo, PcLookup, PcMatchType, PcRow, PcZone}, store::CacheStore};
 
 pub async fn find_records(client: &Client, coll_name: &str, limit: u64, skip: u64, filter_options: Option<Document>, fields: Option<Vec<&str>>) -> Vec<Document> {
   let db_name = get_db_name();
@@ -73,15 +73,14 @@ pub async fn update_record(client: &Client, coll_name: &str, filter_options: &Do
   cursor_r.is_ok()
 }
 
-pub async fn fetch_aggregated_with_options(client: &Client, coll_name: &str, pipeline: Vec<Document>, options: Option<AggregateOptions>) -> Vec<Document> {
+pub async fn fetch_aggregated_with_options(client: &Client, coll_name: &str, pipeline: Vec<Document>, options: Option<AggregateOptions>) -> GeoFinderResult<Vec<Document>> {
   let db_name = get_db_name();
   let coll: Collection<Document> = client
         .database(&db_name)
         .collection::<Document>(coll_name);
     let cursor = coll
         .aggregate(pipeline, options)
-        .await
-        .expect("could not load data.");
+        .await?;
     let results: Vec<mongodb::error::Result<Document>> = cursor.collect().await;
     let mut rows: Vec<Document> = Vec::new();
     if results.len() > 0 {
@@ -91,14 +90,26 @@ pub async fn fetch_aggregated_with_options(client: &Client, coll_name: &str, pip
             }
         }
     }
-    rows
+    Ok(rows)
 }
 
-pub async fn fetch_aggregated(client: &Client, coll_name: &str, pipeline: Vec<Document>) -> Vec<Document> {
+pub async fn fetch_aggregated(client: &Client, coll_name: &str, pipeline: Vec<Document>) -> GeoFinderResult<Vec<Document>> {
   fetch_aggregated_with_options(client, coll_name, pipeline, None).await
 }
 
+/// Distance within which the nearest live postcode replaces a terminated one without recorded successors
+pub const SUCCESSOR_KM: f64 = 2.0;
+
+/// Terminated postcodes are kept in zones but excluded from nearby, area and prefix searches
+pub fn live_pc_filter() -> Document {
+  doc! { "terminated": { "$exists": false } }
+}
+
 pub fn build_geo_search(geo: Geo, km: f64) -> Document {
+  build_geo_search_with_query(geo, km, live_pc_filter())
+}
+
+pub fn build_geo_search_with_query(geo: Geo, km: f64, query: Document) -> Document {
   let max_distance_metres = km * 1000f64;
   doc! {
       "$geoNear": {
@@ -109,12 +120,13 @@ pub fn build_geo_search(geo: Geo, km: f64) -> Document {
           "minDistance": 0,
           "maxDistance": max_distance_metres,
           "spherical": true,
-          "distanceField": "distance"
+          "distanceField": "distance",
+          "query": query
       }
     }
 }
 
-pub async fn fetch_pcs(client: &Client, geo: Geo, km: f64, limit: u32) -> Vec<PcRow> {
+pub async fn fetch_pcs(client: &Client, geo: Geo, km: f64, limit: u32) -> GeoFinderResult<Vec<PcRow>> {
   let geo_search = build_geo_search(geo, km);
   let mut pipeline = vec![geo_search];
   let projection = doc! {
@@ -132,31 +144,192 @@ pub async fn fetch_pcs(client: &Client, geo: Geo, km: f64, limit: u32) -> Vec<Pc
   pipeline.push(doc! { "$project": projection } );
   let limit_u32 = if limit < 2 { 2 } else if limit > 1000  { 1000  } else { limit };
   pipeline.push(doc! { "$limit":  limit_u32 } );
-  let rows = fetch_aggregated(client, "zones", pipeline).await;
-  if rows.len() > 0 {
-    rows.into_iter().map(|row| PcRow::new(&row)).collect::<Vec<PcRow>>()
-  } else {
-    vec![]
+  let rows = fetch_aggregated(client, "zones", pipeline).await?;
+  Ok(rows.into_iter().map(|row| PcRow::new(&row)).collect::<Vec<PcRow>>())
+}
+
+pub fn build_geo_within(rings: Vec<Vec<Vec<f64>>>) -> Document {
+  let geo_field = get_zones_geo_field();
+  // drop any altitude values so positions are plain [lng, lat] pairs
+  let coordinates: Vec<Vec<Vec<f64>>> = rings.into_iter()
+    .map(|ring| ring.into_iter().map(|pos| pos.into_iter().take(2).collect()).collect())
+    .collect();
+  doc! {
+    "$match": {
+      geo_field: {
+        "$geoWithin": {
+          "$geometry": {
+            "type": "Polygon",
+            "coordinates": coordinates
+          }
+        }
+      },
+      "terminated": { "$exists": false }
+    }
   }
 }
 
-pub async fn match_pc_zone(client: &Client, pc_str: &str) -> Option<PcZone> {
-  let pc = pc_str.trim().to_uppercase().pattern_replace_cs("\\s+", " ");
+/// Postcodes inside a polygon, sorted by postcode so skip/limit pages are stable
+pub async fn fetch_pcs_within(client: &Client, rings: Vec<Vec<Vec<f64>>>, limit: u32, skip: u32) -> GeoFinderResult<Vec<PcRow>> {
+  let mut pipeline = vec![build_geo_within(rings)];
+  pipeline.push(doc! { "$sort": { "pc": 1 } });
+  if skip > 0 {
+    pipeline.push(doc! { "$skip": skip });
+  }
+  let limit_u32 = limit.clamp(1, 1000);
+  pipeline.push(doc! { "$limit":  limit_u32 } );
+  let projection = doc! {
+    "_id": 0,
+    "lat": 1,
+    "lng": 1,
+    "pc": 1,
+    "c": 1,
+    "cy": 1,
+    "d": 1,
+    "lc": 1,
+    "w": 1
+  };
+  pipeline.push(doc! { "$project": projection } );
+  let rows = fetch_aggregated(client, "zones", pipeline).await?;
+  Ok(rows.into_iter().map(|row| PcRow::new(&row)).collect::<Vec<PcRow>>())
+}
+
+pub async fn match_pc_zone(client: &Client, store: &dyn CacheStore, pc_str: &str) -> Option<PcZone> {
+  let pc = match parse_uk_postcode(pc_str) {
+    Some((code, PcMatchType::Full)) => code,
+    _ => pc_str.trim().to_uppercase().pattern_replace_cs("\\s+", " ")
+  };
   let cache_key = format!("pc_zone_{}", pc);
-  if let Some(pc_zone) = redis_get_postcode(&cache_key) {
+  if let Some(pc_zone) = store.get_postcode(&cache_key).await {
     return Some(pc_zone);
   } else {
-    let rgx_str = format!("^\\s*{}\\s*$", pc.pattern_replace_cs("\\s+", "\\s+"));
-    let filter_options = Some(doc! { "pc": { "$regex": rgx_str }} );
+    let filter_options = Some(build_pc_code_filter(&pc, PcMatchType::Full));
     if let Some(data) = fetch_record(client, "zones", filter_options).await {
-      let pc_zone = PcZone::new(&data);
-      redis_set_postcode(&cache_key, &pc_zone);
+      let pc_zone = resolve_pc_zone(client, PcZone::new(&data)).await;
+      store.set_postcode(&cache_key, &pc_zone).await;
       return Some(pc_zone);
     }
   }
   None
 }
 
+/// Terminated postcodes resolve to their nearest live successor, which records the redirect.
+/// A terminated postcode without a live successor is returned as it is.
+pub async fn resolve_pc_zone(client: &Client, pc_zone: PcZone) -> PcZone {
+  if !pc_zone.is_terminated() {
+    return pc_zone;
+  }
+  match fetch_live_successor(client, &pc_zone).await {
+    Some(mut successor) => {
+      successor.set_redirect(&pc_zone);
+      successor
+    },
+    None => pc_zone
+  }
+}
+
+/// Nearest live postcode among the recorded successors of a terminated postcode,
+/// or the nearest live postcode within SUCCESSOR_KM if none were recorded
+pub async fn fetch_live_successor(client: &Client, pc_zone: &PcZone) -> Option<PcZone> {
+  let mut query = live_pc_filter();
+  if !pc_zone.successors.is_empty() {
+    query.insert("pc", doc! { "$in": pc_zone.successors.clone() });
+  }
+  if pc_zone.has_geo() {
+    // recorded successors may lie further away than a nearby live postcode
+    let km = if pc_zone.successors.is_empty() { SUCCESSOR_KM } else { 100.0 };
+    let pipeline = vec![
+      build_geo_search_with_query(Geo::simple(pc_zone.lat, pc_zone.lng), km, query),
+      doc! { "$limit": 1 },
+      doc! { "$project": { "_id": 0, get_zones_geo_field(): 0 } },
+    ];
+    let rows = fetch_aggregated(client, "zones", pipeline).await.ok()?;
+    return rows.first().map(PcZone::new);
+  }
+  if pc_zone.successors.is_empty() {
+    return None;
+  }
+  let rows = find_records(client, "zones", 0, 0, Some(query), None).await;
+  let zones: Vec<PcZone> = rows.iter().map(PcZone::new).collect();
+  pc_zone.successors.iter().find_map(|pc| zones.iter().find(|zone| zone.pc == *pc).cloned())
+}
+
+/// Live postcodes nearest to a point, used to record the successors of a terminated postcode
+pub async fn fetch_live_pcs_near(client: &Client, geo: Geo, km: f64, limit: u32) -> GeoFinderResult<Vec<String>> {
+  let pipeline = vec![
+    build_geo_search(geo, km),
+    doc! { "$limit": limit },
+    doc! { "$project": { "_id": 0, "pc": 1 } },
+  ];
+  let rows = fetch_aggregated(client, "zones", pipeline).await?;
+  Ok(rows.into_iter().filter_map(|row| row.get_str("pc").ok().map(|pc| pc.to_owned())).collect())
+}
+
+/// Exact match for full postcodes, otherwise a case-sensitive regex anchored at the start
+/// so MongoDB can answer it from the pc index as a prefix range.
+/// Outward codes end with a space so that "W1" does not also match "W10" or "W1A".
+/// Full matches include terminated postcodes so they can be redirected.
+pub fn build_pc_code_filter(code: &str, match_type: PcMatchType) -> Document {
+  match match_type {
+    PcMatchType::Full => doc! { "pc": code },
+    PcMatchType::Sector => doc! { "pc": { "$regex": format!("^{}", code) }, "terminated": { "$exists": false } },
+    PcMatchType::Outcode => doc! { "pc": { "$regex": format!("^{} ", code) }, "terminated": { "$exists": false } },
+  }
+}
+
+pub async fn fetch_pc_lookup(client: &Client, pc_str: &str, limit: u32, skip: u32) -> GeoFinderResult<PcLookup> {
+  let (code, match_type) = parse_uk_postcode(pc_str)
+    .ok_or(GeoFinderError::BadInput(format!("{} is not a UK postcode, sector or outward code", pc_str)))?;
+  let filter = build_pc_code_filter(&code, match_type);
+  if match_type == PcMatchType::Full {
+    let data = fetch_record(client, "zones", Some(filter)).await
+      .ok_or(GeoFinderError::NotFound(format!("postcode {} not found", code)))?;
+    return Ok(PcLookup::new_full(resolve_pc_zone(client, PcZone::new(&data)).await));
+  }
+  let summary_pipeline = vec![
+    doc! { "$match": filter.clone() },
+    doc! { "$group": {
+      "_id": Bson::Null,
+      "count": { "$sum": 1 },
+      "lat": { "$avg": "$lat" },
+      "lng": { "$avg": "$lng" },
+      "minLat": { "$min": "$lat" },
+      "minLng": { "$min": "$lng" },
+      "maxLat": { "$max": "$lat" },
+      "maxLng": { "$max": "$lng" },
+    } }
+  ];
+  let summary = fetch_aggregated(client, "zones", summary_pipeline).await?
+    .into_iter().next()
+    .ok_or(GeoFinderError::NotFound(format!("no postcodes found for {}", code)))?;
+  let mut pipeline = vec![
+    doc! { "$match": filter },
+    doc! { "$sort": { "pc": 1 } },
+  ];
+  if skip > 0 {
+    pipeline.push(doc! { "$skip": skip });
+  }
+  pipeline.push(doc! { "$limit": limit.clamp(1, 1000) });
+  pipeline.push(doc! { "$project": { "_id": 0, get_zones_geo_field(): 0 } });
+  let zones = fetch_aggregated(client, "zones", pipeline).await?
+    .into_iter().map(|row| PcZone::new(&row)).collect::<Vec<PcZone>>();
+  Ok(PcLookup::new_partial(&code, match_type, &summary, zones))
+}
+
+/// Postcodes starting with a normalised prefix, in alphabetical order.
+/// The pc index only bounds the scan by the regex's literal prefix, so for a compact prefix
+/// with an optional space, e.g. "SW1A1", every postcode in the outward code SW1A is scanned.
+pub async fn fetch_pc_prefix_matches(client: &Client, prefix: &str, limit: u32) -> GeoFinderResult<Vec<String>> {
+  let pipeline = vec![
+    doc! { "$match": { "pc": { "$regex": pc_prefix_regex(prefix) }, "terminated": { "$exists": false } } },
+    doc! { "$sort": { "pc": 1 } },
+    doc! { "$limit": limit },
+    doc! { "$project": { "_id": 0, "pc": 1 } },
+  ];
+  let rows = fetch_aggregated(client, "zones", pipeline).await?;
+  Ok(rows.into_iter().filter_map(|row| row.get_str("pc").ok().map(|pc| pc.to_owned())).collect())
+}
+
 /* pub async fn find_nearby_pcs(client: &Client, pc_str: &str, limit: u32) -> Vec<PcZone> {
   let mut rows: Vec<PcZone> = Vec::new();
   if let Some(pc_zone) = match_pc_zone(client, pc_str).await {
@@ -172,7 +345,7 @@ pub async fn match_pc_zone(client: &Client, pc_str: &str) -> Option<PcZone> {
   rows
 } */
 
-pub async fn fetch_pc_zones(client: &Client, geo: Geo, km: f64, limit: u32, exclude_pc: Option<&str>) -> Vec<PcZone> {
+pub async fn fetch_pc_zones(client: &Client, geo: Geo, km: f64, limit: u32, exclude_pc: Option<&str>) -> GeoFinderResult<Vec<PcZone>> {
   let geo_search = build_geo_search(geo, km); 
   let mut pipeline = vec![geo_search];
   if let Some(pc) =  exclude_pc {
@@ -200,22 +373,18 @@ pub async fn fetch_pc_zones(client: &Client, geo: Geo, km: f64, limit: u32, excl
   pipeline.push(doc! { "$project": projection } );
   let limit_u32 = if limit < 2 { 2 } else if limit > 1000  { 1000  } else { limit };
   pipeline.push(doc! { "$limit":  limit_u32 } );
-  let rows = fetch_aggregated(client, "zones", pipeline).await;
-  if rows.len() > 0 {
-    rows.into_iter().map(|row| PcZone::new(&row)).collect::<Vec<PcZone>>()
-  } else {
-    vec![]
-  }
+  let rows = fetch_aggregated(client, "zones", pipeline).await?;
+  Ok(rows.into_iter().map(|row| PcZone::new(&row)).collect::<Vec<PcZone>>())
 }
 
-pub async fn get_nearest_pc_info(client: &Client, geo: Geo) -> Option<PcInfo> {
+pub async fn get_nearest_pc_info(client: &Client, store: &dyn CacheStore, geo: Geo) -> Option<PcInfo> {
   let ck = build_store_key_from_geo("pc", geo, Some(15.0), Some(1), 6);
-  let mut rows = redis_get_pc_results(&ck);
+  let mut rows = store.get_pc_results(&ck).await;
   let mut info: Option<PcInfo> = None;
   if rows.len() < 1 {
-    rows = fetch_pcs(&client, geo, 15.0, 1).await;
+    rows = fetch_pcs(&client, geo, 15.0, 1).await.unwrap_or_default();
     if rows.len() > 0 {
-      redis_set_pc_results(&ck, &rows);
+      store.set_pc_results(&ck, &rows).await;
     }
   }
   if rows.len() > 0 {
@@ -235,54 +404,6 @@ pub async fn update_pc_addresses(client: &Client, pc: &str, addresses: &[String]
 
 pub async fn fetch_pc_zone(client: &Client, pc: &str) -> Option<PcZone> {
   let filter = Some(doc ! { "pc": pc });
-  let result = fetch_record(client, "zones",filter).await;
-  result.map(|item| PcZone::new(&item))
-}
-
-/* fn extract_string_from_vec(vals: &Vec<String>, index: usize) -> String {
-  vals.get(index).unwrap_or(&"".to_string()).to_owned()
-} */
-
-/* 
-pub async fn get_update_lines(client: &Client) -> usize {
-  let text_lines = read_lines("/Users/neil/apps/laravel/geotime-zone-extra-sources/dg_ng_export.tsv");
-  let mut counter: usize = 0;
-  let mut index = 0;
-  let start = 0;
-  for line in text_lines {
-    if index >= start {
-      let cells = line.to_parts("\t");
-      if cells.len() > 4 {
-        if let Some(pc) = cells.get(0) {
-          if pc.len() > 3 {
-            let updated = update_incomplete_pc_zone(client, &cells).await;
-            if updated {
-              counter += 1;
-            }
-          }
-        }
-      }
-      if counter > 10000 {
-        break;
-      }
-    }
-    index += 1;
-  }
-  counter
+  let data = fetch_record(client, "zones",filter).await?;
+  Some(resolve_pc_zone(client, PcZone::new(&data)).await)
 }
-
-pub async fn update_incomplete_pc_zone(client: &Client, vals: &Vec<String>) -> bool {
-  if let Some(first) = vals.get(0) {
-    let pc = first.to_owned().clone();
-    let query = doc ! { "pc": pc, "w": { "$exists": false } };
-    let n = extract_string_from_vec(vals, 1);
-    let e = extract_string_from_vec(vals, 2);
-    let w = extract_string_from_vec(vals, 3);
-    let cy = extract_string_from_vec(vals, 4);
-    let d = extract_string_from_vec(vals, 5);
-    let gr = extract_string_from_vec(vals, 6);
-    let data = doc ! { "n": n, "e": e, "w": w, "cy": cy, "d": d, "gr": gr };
-    return update_record(client, "zones",&query, &data).await;
-  }
-  false
-} */
\ No newline at end of file
@@ -0,0 +1,412 @@
+use std::{collections::HashMap, fs::File, io::{BufRead, BufReader}, path::Path, sync::Arc};
+use rstar::{primitives::GeomWithData, RTree};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::{common::get_gazetteer_file, models::{Geo, GeoNearby}};
+
+/// Populated place from a GeoNames dump. Admin and country names are shared between places.
+pub struct GazetteerPlace {
+  pub name: String,
+  pub lat: f64,
+  pub lng: f64,
+  pub fclass: char,
+  pub fcode: String,
+  pub cc: String,
+  pub admin_name: Arc<str>,
+  pub region: Arc<str>,
+  pub country_name: Arc<str>,
+  pub population: u32,
+  pub zone_name: Option<Arc<str>>,
+}
+
+impl GazetteerPlace {
+  /// Place name with its region and country, e.g. "Cambridge, England, United Kingdom"
+  pub fn to_text(&self) -> String {
+    let mut parts: Vec<&str> = vec![self.name.as_str()];
+    for part in [self.region.as_ref(), self.country_name.as_ref()] {
+      if !part.is_empty() && !parts.contains(&part) {
+        parts.push(part);
+      }
+    }
+    parts.join(", ")
+  }
+
+  pub fn to_geo_nearby(&self, distance: f64) -> GeoNearby {
+    GeoNearby {
+      lng: self.lng,
+      lat: self.lat,
+      name: self.name.clone(),
+      toponym: self.name.clone(),
+      fcode: self.fcode.clone(),
+      distance,
+      pop: self.population,
+      admin_name: self.admin_name.to_string(),
+      region: self.region.to_string(),
+      cc: Some(self.cc.clone()),
+      country_name: self.country_name.to_string(),
+      zone_name: self.zone_name.as_ref().map(|zn| zn.to_string()),
+      pc: None
+    }
+  }
+}
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+pub fn haversine_km(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+  let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+  let d_phi = (lat2 - lat1).to_radians();
+  let d_lambda = (lng2 - lng1).to_radians();
+  let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+  2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// Nearest neighbours in degrees only approximate great-circle order away from the equator,
+/// so this many candidates are compared by true distance
+const NEAREST_CANDIDATES: usize = 16;
+
+type PlacePoint = GeomWithData<[f64; 2], usize>;
+
+/// Names keyed by GeoNames code, e.g. GB.ENG for admin1 or GB for countries
+type CodeNames = HashMap<String, Arc<str>>;
+
+/// Populated places from a GeoNames citiesNNNN.txt or allCountries.txt dump indexed by location.
+/// Admin and country names are read from admin1CodesASCII.txt, admin2Codes.txt and
+/// countryInfo.txt in the same directory when present.
+#[derive(Default)]
+pub struct Gazetteer {
+  places: Vec<GazetteerPlace>,
+  tree: RTree<PlacePoint>,
+  names: NameIndex,
+}
+
+pub type SharedGazetteer = Arc<Gazetteer>;
+
+impl Gazetteer {
+  pub fn empty() -> Self {
+    Gazetteer::default()
+  }
+
+  /// Loads the dump named by GAZETTEER_FILE, or an empty gazetteer if unset or unreadable
+  pub fn from_env() -> Self {
+    match get_gazetteer_file() {
+      Some(path) => match Gazetteer::load(&path) {
+        Ok(gazetteer) => {
+          tracing::info!("loaded {} gazetteer places from {}", gazetteer.len(), path);
+          gazetteer
+        },
+        Err(message) => {
+          tracing::warn!("gazetteer unavailable: {}", message);
+          Gazetteer::empty()
+        }
+      },
+      None => Gazetteer::empty()
+    }
+  }
+
+  /// Reads populated places (feature class P) from a tab-separated GeoNames dump
+  pub fn load(path: &str) -> Result<Self, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    let admin1_names = read_code_names(&dir.join("admin1CodesASCII.txt"), 0, 1);
+    let admin2_names = read_code_names(&dir.join("admin2Codes.txt"), 0, 1);
+    let country_names = read_code_names(&dir.join("countryInfo.txt"), 0, 4);
+    let mut zone_names: HashMap<String, Arc<str>> = HashMap::new();
+    let empty: Arc<str> = Arc::from("");
+    let mut places: Vec<GazetteerPlace> = vec![];
+    let mut names = NameIndex::default();
+    for line in BufReader::new(file).lines() {
+      let line = line.map_err(|e| format!("{}: {}", path, e))?;
+      let cols: Vec<&str> = line.split('\t').collect();
+      if cols.len() < 18 || cols[6] != "P" {
+        continue;
+      }
+      let (Ok(lat), Ok(lng)) = (cols[4].parse::<f64>(), cols[5].parse::<f64>()) else {
+        continue;
+      };
+      let cc = cols[8].to_string();
+      let admin1_key = format!("{}.{}", cc, cols[10]);
+      let region = admin1_names.get(&admin1_key).cloned().unwrap_or(empty.clone());
+      let admin_name = admin2_names.get(&format!("{}.{}", admin1_key, cols[11])).cloned().unwrap_or(region.clone());
+      let zone_name = Some(cols[17]).filter(|zn| !zn.is_empty())
+        .map(|zn| zone_names.entry(zn.to_string()).or_insert_with(|| Arc::from(zn)).clone());
+      let alternate_names = cols[3].split(',').filter(|alt| alt.len() <= MAX_NAME_LEN);
+      names.add(places.len(), [cols[1], cols[2]].into_iter().chain(alternate_names));
+      places.push(GazetteerPlace {
+        name: cols[1].to_string(),
+        lat,
+        lng,
+        fclass: 'P',
+        fcode: cols[7].to_string(),
+        country_name: country_names.get(&cc).cloned().unwrap_or(empty.clone()),
+        cc,
+        admin_name,
+        region,
+        population: cols[14].parse::<u32>().unwrap_or(0),
+        zone_name
+      });
+    }
+    if places.is_empty() {
+      return Err(format!("{} has no populated places", path));
+    }
+    let populations: Vec<u32> = places.iter().map(|place| place.population).collect();
+    names.sort_postings(&populations);
+    let points = places.iter().enumerate().map(|(index, place)| PlacePoint::new([place.lng, place.lat], index)).collect();
+    Ok(Gazetteer {
+      places,
+      tree: RTree::bulk_load(points),
+      names
+    })
+  }
+
+  pub fn len(&self) -> usize {
+    self.places.len()
+  }
+
+  pub fn is_loaded(&self) -> bool {
+    !self.places.is_empty()
+  }
+
+  /// Nearest populated place with its distance in km, as the GeoTimeZone /geotz endpoint reports it
+  pub fn nearest(&self, geo: Geo) -> Option<GeoNearby> {
+    self.tree.nearest_neighbor_iter(&[geo.lng, geo.lat])
+      .take(NEAREST_CANDIDATES)
+      .map(|point| {
+        let place = &self.places[point.data];
+        (place, haversine_km(geo.lat, geo.lng, place.lat, place.lng))
+      })
+      .min_by(|a, b| a.1.total_cmp(&b.1))
+      .map(|(place, distance)| place.to_geo_nearby(distance))
+  }
+
+  /// Places whose name, ASCII name or an alternate name matches the search, best match first.
+  /// With fuzzy 0 names must start with the search, otherwise any name whose trigram similarity
+  /// is at least 1 - fuzzy / 100 also matches. Each place is scored by its best name similarity
+  /// blended with population and, given a bias point, proximity.
+  pub fn search(&self, search: &str, cc_opt: Option<&str>, fuzzy: u32, near_opt: Option<Geo>, limit: usize) -> Vec<PlaceMatch<'_>> {
+    let query = normalize_name(search);
+    if query.is_empty() {
+      return vec![];
+    }
+    let min_similarity = 1.0 - fuzzy.min(100) as f64 / 100.0;
+    let mut best: HashMap<usize, f64> = HashMap::new();
+    for (name_index, similarity) in self.names.candidates(&query) {
+      let (name, place_index) = &self.names.names[name_index];
+      let is_prefix = name.starts_with(query.as_str());
+      let matched = if fuzzy == 0 { is_prefix } else { is_prefix || similarity >= min_similarity };
+      if !matched {
+        continue;
+      }
+      let place = &self.places[*place_index];
+      if cc_opt.map(|cc| !place.cc.eq_ignore_ascii_case(cc)).unwrap_or(false) {
+        continue;
+      }
+      let similarity = if name.as_ref() == query.as_str() { 1.0 } else { similarity };
+      let entry = best.entry(*place_index).or_insert(0.0);
+      if similarity > *entry {
+        *entry = similarity;
+      }
+    }
+    let mut matches: Vec<PlaceMatch> = best.into_iter()
+      .map(|(place_index, similarity)| {
+        let place = &self.places[place_index];
+        let distance = near_opt.map(|near| haversine_km(near.lat, near.lng, place.lat, place.lng));
+        PlaceMatch {
+          place,
+          score: place_score(similarity, place.population, distance),
+          distance
+        }
+      })
+      .collect();
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+    matches.truncate(limit);
+    matches
+  }
+}
+
+/// A place matched by name with its ranking score and km from the bias point, if any
+pub struct PlaceMatch<'a> {
+  pub place: &'a GazetteerPlace,
+  pub score: f64,
+  pub distance: Option<f64>,
+}
+
+/// Blends name similarity with a population weight that saturates at 10 million and,
+/// given a distance from the bias point, a proximity weight that halves at 100 km
+pub fn place_score(similarity: f64, population: u32, distance_km: Option<f64>) -> f64 {
+  let pop_weight = ((population as f64 + 1.0).log10() / 7.0).min(1.0);
+  match distance_km {
+    Some(km) => similarity * 0.6 + pop_weight * 0.15 + 0.25 / (1.0 + km / 100.0),
+    None => similarity * 0.8 + pop_weight * 0.2
+  }
+}
+
+/// Trigram similarity of a search and a place name, 1 when they normalise to the same name
+pub fn name_similarity(search: &str, name: &str) -> f64 {
+  let (query, name) = (normalize_name(search), normalize_name(name));
+  if query.is_empty() || name.is_empty() {
+    return 0.0;
+  }
+  if query == name {
+    return 1.0;
+  }
+  let (query_grams, name_grams) = (trigrams(&query), trigrams(&name));
+  let shared = query_grams.iter().filter(|gram| name_grams.binary_search(gram).is_ok()).count();
+  2.0 * shared as f64 / (query_grams.len() + name_grams.len()) as f64
+}
+
+/// Alternate names longer than this are usually descriptions rather than names
+const MAX_NAME_LEN: usize = 60;
+
+/// Lower-case ASCII with diacritics removed, punctuation as spaces and runs of spaces collapsed,
+/// so "Zürich" and "St. Gallen" become "zurich" and "st gallen". Non-Latin scripts are dropped.
+pub fn normalize_name(name: &str) -> String {
+  let mut folded = String::with_capacity(name.len());
+  for c in name.nfd().filter(|c| !is_combining_mark(*c)) {
+    match c {
+      'ß' => folded.push_str("ss"),
+      'æ' | 'Æ' => folded.push_str("ae"),
+      'œ' | 'Œ' => folded.push_str("oe"),
+      'ø' | 'Ø' => folded.push('o'),
+      'ł' | 'Ł' => folded.push('l'),
+      'đ' | 'Đ' => folded.push('d'),
+      'þ' | 'Þ' => folded.push_str("th"),
+      c if c.is_ascii_alphanumeric() => folded.push(c.to_ascii_lowercase()),
+      c if c.is_ascii() => folded.push(' '),
+      _ => {}
+    }
+  }
+  folded.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Trigrams of a normalised name padded as in PostgreSQL's pg_trgm, so short names and word starts
+/// produce trigrams of their own
+fn trigrams(name: &str) -> Vec<[u8; 3]> {
+  let padded: Vec<u8> = format!("  {} ", name).into_bytes();
+  let mut grams: Vec<[u8; 3]> = padded.windows(3).map(|w| [w[0], w[1], w[2]]).collect();
+  grams.sort();
+  grams.dedup();
+  grams
+}
+
+/// Trigrams shared by more names than this are skipped when the query has rarer ones,
+/// and no more postings than this, most populous places first, are read from any one trigram
+const MAX_TRIGRAM_POSTINGS: usize = 20000;
+
+/// Trigrams of the padded start of a name, shared by every name beginning with it
+fn prefix_trigrams(name: &str) -> Vec<[u8; 3]> {
+  let padded: Vec<u8> = format!("  {}", name).into_bytes();
+  padded.windows(3).map(|w| [w[0], w[1], w[2]]).collect()
+}
+
+/// Normalised place names with an inverted trigram index
+#[derive(Default)]
+struct NameIndex {
+  names: Vec<(Box<str>, usize)>,
+  trigrams: HashMap<[u8; 3], Vec<u32>>,
+  gram_counts: Vec<u16>,
+}
+
+impl NameIndex {
+  fn add<'a>(&mut self, place_index: usize, names: impl Iterator<Item = &'a str>) {
+    let mut seen: Vec<String> = vec![];
+    for name in names.map(normalize_name).filter(|name| !name.is_empty()) {
+      if seen.contains(&name) {
+        continue;
+      }
+      let name_index = self.names.len() as u32;
+      let grams = trigrams(&name);
+      for gram in grams.iter() {
+        self.trigrams.entry(*gram).or_default().push(name_index);
+      }
+      self.gram_counts.push(grams.len() as u16);
+      self.names.push((name.clone().into_boxed_str(), place_index));
+      seen.push(name);
+    }
+  }
+
+  /// Orders each posting list by descending population, so capped reads keep the largest places
+  fn sort_postings(&mut self, populations: &[u32]) {
+    let names = &self.names;
+    for name_indices in self.trigrams.values_mut() {
+      name_indices.sort_by_key(|name_index| std::cmp::Reverse(populations[names[*name_index as usize].1]));
+    }
+  }
+
+  fn candidates(&self, query: &str) -> Vec<(usize, f64)> {
+    self.candidates_within(query, MAX_TRIGRAM_POSTINGS)
+  }
+
+  /// Names sharing one of the query's rarer trigrams and their Dice similarity.
+  /// The least common trigram from the start of the query is always read, so names starting
+  /// with the query are found even when all its trigrams are common.
+  /// Similarity is counted from each candidate's own trigrams, so skipped common trigrams still score.
+  fn candidates_within(&self, query: &str, max_postings: usize) -> Vec<(usize, f64)> {
+    let query_grams = trigrams(query);
+    let postings_of = |grams: &[[u8; 3]]| -> Vec<&Vec<u32>> {
+      let mut postings: Vec<&Vec<u32>> = grams.iter().filter_map(|gram| self.trigrams.get(gram)).collect();
+      postings.sort_by_key(|name_indices| name_indices.len());
+      postings
+    };
+    let mut postings: Vec<&Vec<u32>> = postings_of(&query_grams).into_iter()
+      .filter(|name_indices| name_indices.len() <= max_postings)
+      .collect();
+    // every name starting with the query has its leading trigrams, but not the padded last one
+    if let Some(prefix_postings) = postings_of(&prefix_trigrams(query)).first() {
+      postings.push(prefix_postings);
+    }
+    let mut name_indices: Vec<u32> = postings.into_iter()
+      .flat_map(|name_indices| name_indices.iter().take(max_postings).copied())
+      .collect();
+    name_indices.sort_unstable();
+    name_indices.dedup();
+    name_indices.into_iter().map(|name_index| {
+      let name_grams = trigrams(&self.names[name_index as usize].0);
+      let shared = query_grams.iter().filter(|gram| name_grams.binary_search(gram).is_ok()).count();
+      let total = query_grams.len() + self.gram_counts[name_index as usize] as usize;
+      (name_index as usize, 2.0 * shared as f64 / total as f64)
+    }).collect()
+  }
+}
+
+/// Reads a tab-separated GeoNames code list, skipping comment lines
+fn read_code_names(path: &Path, code_col: usize, name_col: usize) -> CodeNames {
+  let mut names = CodeNames::new();
+  if let Ok(file) = File::open(path) {
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+      if line.starts_with('#') {
+        continue;
+      }
+      let cols: Vec<&str> = line.split('\t').collect();
+      if let (Some(code), Some(name)) = (cols.get(code_col), cols.get(name_col)) {
+        names.insert(code.to_string(), Arc::from(*name));
+      }
+    }
+  }
+  names
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn capped_postings_keep_the_most_populous_prefix_matches() {
+    let mut index = NameIndex::default();
+    let mut populations: Vec<u32> = vec![];
+    // many small places sharing the leading trigrams of "pa", loaded before the largest one
+    for num in 0..50 {
+      index.add(populations.len(), [format!("pa{}", num).as_str()].into_iter());
+      populations.push(100 + num);
+    }
+    index.add(populations.len(), ["Paris"].into_iter());
+    populations.push(2_100_000);
+    index.sort_postings(&populations);
+    let max_postings = 10;
+    assert!(index.trigrams.get(b" pa").map(|postings| postings.len()).unwrap_or(0) > max_postings);
+    let names: Vec<&str> = index.candidates_within("pa", max_postings).into_iter()
+      .map(|(name_index, _)| index.names[name_index].0.as_ref())
+      .collect();
+    assert!(names.contains(&"paris"));
+    assert!(names.len() <= max_postings + 1);
+  }
+}
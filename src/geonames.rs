@@ -1,5 +1,6 @@
-use crate::{common::get_geonames_username, models::{build_pois, build_postcodes, build_wiki_summaries, Geo, PcZone, PlaceOfInterest, WeatherReport, WikipediaSummary}, store::{redis_get_poi, redis_get_weather, redis_get_wiki_summaries, redis_set_poi, redis_set_weather, redis_set_wiki_summaries}};
+use crate::{common::get_geonames_username, errors::{GeoFinderError, GeoFinderResult}, upstream::{Upstream, UpstreamClient}, models::{build_pois, build_postcodes, build_wiki_summaries, Geo, PcZone, PlaceOfInterest, WeatherReport, WikipediaSummary}, store::CacheStore};
 use serde_json::*;
+use crate::extractors::{extract_string_from_value_map, extract_u32_from_value_map};
 
 pub const GEONAMES_BASE_URI: &'static str = "http://api.geonames.org";
 
@@ -28,8 +29,7 @@ impl GeoNamesService {
 }
 
 
-async fn fetch_from_geonames(geo: Geo, service: GeoNamesService) -> Option<Map<String, Value>> {
-  let req_client = reqwest::Client::new();
+async fn fetch_from_geonames(upstream: &UpstreamClient, geo: Geo, service: GeoNamesService) -> GeoFinderResult<Map<String, Value>> {
   let username = get_geonames_username();
   let mut query_params = vec![
     ("username", username),
@@ -46,99 +46,114 @@ async fn fetch_from_geonames(geo: Geo, service: GeoNamesService) -> Option<Map<S
     }
   };
   let uri = format!("{}/{}", GEONAMES_BASE_URI, service.to_method_name());
-  let result = req_client.get(&uri)
-    .query(&query_params).send()
-    .await
-    .expect("failed to get response")
-    .text()
-    .await;
-  if let Ok(result_string) = result {
-    if let Ok(json) = serde_json::from_str(&result_string) {
-      Some(json)
-    } else {
-      None
-    }
-  } else {
-    None
+  let request = upstream.get(Upstream::GeoNames, &uri).query(&query_params);
+  upstream.send_json_validated(request, Upstream::GeoNames, check_geonames_status).await
+}
+
+/// GeoNames reports errors such as exceeded credits with a 200 status and a status object.
+/// Code 15 means no result, other codes are quota, credit or authentication failures.
+pub fn check_geonames_status(data: &Map<String, Value>) -> GeoFinderResult<()> {
+  if let Some(status) = data.get("status").and_then(|st| st.as_object()) {
+    let code = extract_u32_from_value_map(status, "value");
+    let message = extract_string_from_value_map(status, "message");
+    tracing::warn!("geonames status {}: {}", code, message);
+    return Err(match code {
+      15 => GeoFinderError::NotFound(message),
+      _ => GeoFinderError::UpstreamUnavailable(Upstream::GeoNames.name().to_string())
+    });
   }
+  Ok(())
 }
 
-pub async fn fetch_poi_cached(geo: Geo) -> (Option<Vec<PlaceOfInterest>>, bool) {
+pub async fn fetch_poi_cached(store: &dyn CacheStore, upstream: &UpstreamClient, geo: Geo) -> GeoFinderResult<(Vec<PlaceOfInterest>, bool)> {
   let ck = format!("plofint_{}", geo.to_approx_key(3));
-  let mut poi_opt:Option<Vec<PlaceOfInterest>> = None;
-  let mut cached = false;
-  if let Some(poi) = redis_get_poi(&ck) {
-    poi_opt = Some(poi);
-    cached = true;
-  } else {
-    if let Some(poi) = fetch_poi(geo).await {
-      poi_opt = Some(poi.clone());
-      redis_set_poi(&ck,&poi);
-    }
+  if let Some(poi) = store.get_poi(&ck).await {
+    return Ok((poi, true));
   }
-  (poi_opt, cached)
+  let poi = fetch_poi(upstream, geo).await?;
+  store.set_poi(&ck,&poi).await;
+  Ok((poi, false))
 }
 
-pub async fn fetch_weather(geo: Geo) -> Option<WeatherReport> {
-  if let Some(data) =  fetch_from_geonames(geo, GeoNamesService::Weather).await {
-    if let Some(inner) = data.get("weatherObservation") {
-      if let Some(inner_map) = inner.as_object() {
-        return Some(WeatherReport::new(inner_map.to_owned()));
-      }
+pub async fn fetch_weather(upstream: &UpstreamClient, geo: Geo) -> GeoFinderResult<WeatherReport> {
+  let data = fetch_from_geonames(upstream, geo, GeoNamesService::Weather).await?;
+  if let Some(inner) = data.get("weatherObservation") {
+    if let Some(inner_map) = inner.as_object() {
+      return Ok(WeatherReport::new(inner_map.to_owned()));
     }
   }
-  None
+  Err(GeoFinderError::NotFound("no weather observation found".to_string()))
 }
 
-pub async fn fetch_weather_cached(geo: Geo) -> (Option<WeatherReport>, bool) {
-  let mut weather_opt: Option<WeatherReport> = None;
-  let mut cached = false;
+pub async fn fetch_weather_cached(store: &dyn CacheStore, upstream: &UpstreamClient, geo: Geo) -> GeoFinderResult<(WeatherReport, bool)> {
   let ck = format!("weather_{}", geo.to_approx_key(1));
-    if let Some(weather) = redis_get_weather(&ck) {
-      weather_opt = Some(weather);
-      cached = true;
-    } else {
-      if let Some(weather) = fetch_weather(geo).await {
-        weather_opt = Some(weather.clone());
-        redis_set_weather(&ck,&weather);
-      }
-    }
-  (weather_opt, cached)
+  if let Some(weather) = store.get_weather(&ck).await {
+    return Ok((weather, true));
+  }
+  let weather = fetch_weather(upstream, geo).await?;
+  store.set_weather(&ck,&weather).await;
+  Ok((weather, false))
 }
 
-pub async fn fetch_poi(geo: Geo) -> Option<Vec<PlaceOfInterest>> {
-  if let Some(data) =  fetch_from_geonames(geo, GeoNamesService::PlacesOfInterest).await {
-    return Some(build_pois(data));
-  }
-  None
+pub async fn fetch_poi(upstream: &UpstreamClient, geo: Geo) -> GeoFinderResult<Vec<PlaceOfInterest>> {
+  let data = fetch_from_geonames(upstream, geo, GeoNamesService::PlacesOfInterest).await?;
+  Ok(build_pois(data))
 }
 
-pub async fn fetch_wiki_entries(geo: Geo) -> Option<Vec<WikipediaSummary>> {
-  if let Some(data) =  fetch_from_geonames(geo, GeoNamesService::Wikipedia).await {
-    return Some(build_wiki_summaries(data));
-  }
-  None
+pub async fn fetch_wiki_entries(upstream: &UpstreamClient, geo: Geo) -> GeoFinderResult<Vec<WikipediaSummary>> {
+  let data = fetch_from_geonames(upstream, geo, GeoNamesService::Wikipedia).await?;
+  Ok(build_wiki_summaries(data))
 }
 
-pub async fn fetch_wiki_entries_cached(geo: Geo) -> (Option<Vec<WikipediaSummary>>, bool) {
-  let mut items_opt: Option<Vec<WikipediaSummary>> = None;
-  let mut cached = false;
+pub async fn fetch_wiki_entries_cached(store: &dyn CacheStore, upstream: &UpstreamClient, geo: Geo) -> GeoFinderResult<(Vec<WikipediaSummary>, bool)> {
   let ck = format!("wiki_{}", geo.to_approx_key(3));
-  if let Some(stored_items) = redis_get_wiki_summaries(&ck) {
-    cached = true;
-    items_opt = Some(stored_items);
-  } else {
-    if let Some(items) = fetch_wiki_entries(geo).await {
-      items_opt = Some(items.clone());
-      redis_set_wiki_summaries(&ck, &items);
-    }
+  if let Some(stored_items) = store.get_wiki_summaries(&ck).await {
+    return Ok((stored_items, true));
   }
-  (items_opt, cached)
+  let items = fetch_wiki_entries(upstream, geo).await?;
+  store.set_wiki_summaries(&ck, &items).await;
+  Ok((items, false))
 }
 
-pub async fn fetch_postcodes(geo: Geo) -> Option<Vec<PcZone>> {
-  if let Some(data) =  fetch_from_geonames(geo, GeoNamesService::Postcode).await {
-    return Some(build_postcodes(data));
+pub async fn fetch_postcodes(upstream: &UpstreamClient, geo: Geo) -> GeoFinderResult<Vec<PcZone>> {
+  let data = fetch_from_geonames(upstream, geo, GeoNamesService::Postcode).await?;
+  Ok(build_postcodes(data))
+}
+
+#[cfg(test)]
+mod tests {
+  use axum::{routing::get, Json, Router};
+  use crate::common::get_breaker_threshold;
+  use super::*;
+
+  /// Serves a GeoNames credit error with a 200 status and returns the base URI
+  async fn serve_status_error() -> String {
+    let app = Router::new().route("/weather", get(|| async {
+      Json(json!({ "status": { "value": 18, "message": "the daily limit of 20000 credits has been exceeded" } }))
+    }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      axum::serve(listener, app).await.unwrap();
+    });
+    format!("http://{}", addr)
+  }
+
+  fn geonames_breaker_open(upstream: &UpstreamClient) -> bool {
+    upstream.breaker_statuses().into_iter().any(|status| status.service == Upstream::GeoNames.name() && status.open)
+  }
+
+  #[tokio::test]
+  async fn status_errors_in_ok_responses_open_the_breaker() {
+    let base_uri = serve_status_error().await;
+    let upstream = UpstreamClient::new();
+    let uri = format!("{}/weather", base_uri);
+    for _ in 0..get_breaker_threshold() {
+      assert!(!geonames_breaker_open(&upstream));
+      let request = upstream.get(Upstream::GeoNames, &uri);
+      let result = upstream.send_json_validated::<Map<String, Value>, _>(request, Upstream::GeoNames, check_geonames_status).await;
+      assert!(matches!(result, Err(GeoFinderError::UpstreamUnavailable(_))));
+    }
+    assert!(geonames_breaker_open(&upstream));
   }
-  None
 }
@@ -1,9 +1,25 @@
-use serde_json::{Error, Map, Value};
+use serde_json::{Map, Value};
+use chrono::Utc;
 use mongodb::Client;
-use crate::{common::{get_gtz_url, is_valid_zone_name}, fetchers::get_nearest_pc_info, models::{Geo, GeoNearby, GeoTimeInfo, PcZone, PlaceRow, TzRow}};
+use crate::{common::{build_store_key_from_geo, get_gtz_url, is_valid_zone_name}, errors::{GeoFinderError, GeoFinderResult}, fetchers::get_nearest_pc_info, gazetteer::{haversine_km, name_similarity, place_score, Gazetteer, SharedGazetteer}, models::{Geo, GeoNearby, GeoTimeInfo, PcZone, PlaceRow, TzRow}, simple_iso::timestamp_from_string, store::CacheStore, timezones::{build_local_tz_row, TzBoundaryIndex}, upstream::{Upstream, UpstreamClient}};
 
-pub async fn get_geotz_data(client: &Client, geo: Geo, date_opt: Option<&str>) -> Option<GeoTimeInfo> {
-  let req_client = reqwest::Client::new();
+/// Nearby place and time for a location. With a loaded gazetteer the place comes from the nearest
+/// populated place and the time from its zone, otherwise both come from the GeoTimeZone API.
+pub async fn get_geotz_data(client: &Client, store: &dyn CacheStore, upstream: &UpstreamClient, tz_index: &TzBoundaryIndex, gazetteer: &Gazetteer, geo: Geo, date_opt: Option<&str>) -> GeoFinderResult<GeoTimeInfo> {
+  if let Some(mut place) = gazetteer.nearest(geo) {
+    if let Some(pc_info) = get_nearest_pc_info(client, store, geo).await {
+      place.add_pc(&pc_info);
+    }
+    let zn_opt = place.zone_name.as_deref();
+    let mut time = match get_local_tz_data(tz_index, Some(geo), zn_opt, date_opt) {
+      Some(time) => time,
+      None => get_tz_data(upstream, tz_index, Some(geo), zn_opt, date_opt).await?
+    };
+    if let Some(cc) = place.cc.as_deref() {
+      time.set_country_code(cc);
+    }
+    return Ok(GeoTimeInfo::new(place, time));
+  }
   let loc = geo.to_string();
   let mut query_params = vec![
     ("loc", loc.as_str()),
@@ -13,30 +29,55 @@ pub async fn get_geotz_data(client: &Client, geo: Geo, date_opt: Option<&str>) -
   }
   let uri = format!("{}/geotz", get_gtz_url());
 
-  let result = req_client.get(&uri)
-    .query(&query_params).send()
-    .await
-    .expect("failed to get response")
-    .text()
-    .await;
-  if let Ok(result_string) = result {
-    let data: Map<String, Value> = serde_json::from_str(&result_string).unwrap();
-    if let Some(place_data) = data.get("place") {
-      if let Some(pd) = place_data.as_object() {
-        let mut place = GeoNearby::new(pd);
-        if let Some(pc_info) = get_nearest_pc_info(client, geo).await {
-          place.add_pc(&pc_info);
-        }
-        if let Some(time_data) = data.get("time") {
-          if let Some(td)  = time_data.as_object() {
-            let time = TzRow::new(td);
-            return Some(GeoTimeInfo::new(place, time));
-          }
+  let request = upstream.get(Upstream::GeoTimeZone, &uri).query(&query_params);
+  let data: Map<String, Value> = upstream.send_json(request, Upstream::GeoTimeZone).await?;
+  if let Some(place_data) = data.get("place") {
+    if let Some(pd) = place_data.as_object() {
+      let mut place = GeoNearby::new(pd);
+      if let Some(pc_info) = get_nearest_pc_info(client, store, geo).await {
+        place.add_pc(&pc_info);
+      }
+      if let Some(time_data) = data.get("time") {
+        if let Some(td)  = time_data.as_object() {
+          let mut time = TzRow::new(td);
+          time.calc_solar_offset(geo.lng);
+          return Ok(GeoTimeInfo::new(place, time));
         }
       }
     }
   }
-  None   
+  Err(GeoFinderError::NotFound(format!("no place or time data found near {}", loc)))
+}
+
+/// Nearby place and time for a location, reusing the cached place and timezone when available.
+/// A cached place without a resolvable timezone is returned without time data.
+pub async fn get_geotz_data_cached(client: &Client, store: &dyn CacheStore, upstream: &UpstreamClient, tz_index: &TzBoundaryIndex, gazetteer: &Gazetteer, geo: Geo, date_opt: Option<&str>) -> GeoFinderResult<GeoTimeInfo> {
+  let ck = build_store_key_from_geo("place", geo, None, None, 5);
+  if let Some(gdata) = store.get_geo_nearby(&ck).await {
+    let zn_opt = gdata.zone_name.as_deref();
+    let cache_key = format!("tz_info_{}_{}_{}", zn_opt.unwrap_or(""), geo.to_approx_key(3), date_opt.unwrap_or("a"));
+    let time_opt = if let Some(mut time) = store.get_timezone(&cache_key).await {
+      time.update_time(date_opt.and_then(timestamp_from_string));
+      Some(time)
+    } else if let Ok(time) = get_tz_data(upstream, tz_index, Some(geo), zn_opt, date_opt).await {
+      store.set_timezone(&cache_key, &time).await;
+      Some(time)
+    } else {
+      None
+    };
+    let mut data = if let Some(time) = time_opt {
+      GeoTimeInfo::new(gdata, time)
+    } else {
+      GeoTimeInfo::new_geoplace(gdata)
+    };
+    data.set_cached();
+    return Ok(data);
+  }
+  let data = get_geotz_data(client, store, upstream, tz_index, gazetteer, geo, date_opt).await?;
+  if let Some(place) = data.place.clone() {
+    store.set_geo_nearby(&ck, &place).await;
+  }
+  Ok(data)
 }
 
 pub fn build_pc_zones_from_geo_info(geo: &GeoNearby) -> Vec<PcZone> {
@@ -44,8 +85,14 @@ pub fn build_pc_zones_from_geo_info(geo: &GeoNearby) -> Vec<PcZone> {
   vec![pc_zone]
 }
 
-pub async fn get_tz_data(geo_opt: Option<Geo>, zn_opt: Option<&str>, date_opt: Option<&str>) -> Option<TzRow> {
-  let client = reqwest::Client::new();
+/// Resolves the timezone locally when boundary polygons are loaded, falling back to the GeoTimeZone API
+/// for locations outside the boundaries or zones unknown to the bundled tz database
+pub async fn get_tz_data(upstream: &UpstreamClient, tz_index: &TzBoundaryIndex, geo_opt: Option<Geo>, zn_opt: Option<&str>, date_opt: Option<&str>) -> GeoFinderResult<TzRow> {
+  if tz_index.is_loaded() {
+    if let Some(tz_data) = get_local_tz_data(tz_index, geo_opt, zn_opt, date_opt) {
+      return Ok(tz_data);
+    }
+  }
   let opt_str = if let Some(zn) = zn_opt {
     zn.to_string()
   } else if let Some(geo) = geo_opt {
@@ -61,30 +108,80 @@ pub async fn get_tz_data(geo_opt: Option<Geo>, zn_opt: Option<&str>, date_opt: O
   if date_opt.is_some() {
     query_params.push(("dt", date_opt.unwrap_or("")));
   }
-  if valid {
-    let uri = format!("{}/timezone", get_gtz_url());
-    let result = client.get(&uri)
-      .query(&query_params).send()
-      .await
-      .expect("failed to get response")
-      .text()
-      .await;
-    if let Ok(result_string) = result {
-      let data: Map<String, Value> = serde_json::from_str(&result_string).unwrap();
-      if data.contains_key("abbreviation") {
-        let mut tz_data = TzRow::new(&data);
-        if let Some(geo) = geo_opt {
-          tz_data.calc_solar_offset(geo.lng);
-        }
-        return Some(tz_data);
-      }
+  if !valid {
+    return Err(GeoFinderError::BadInput("a valid zone name (zn) or location (loc) is required".to_string()));
+  }
+  let uri = format!("{}/timezone", get_gtz_url());
+  let request = upstream.get(Upstream::GeoTimeZone, &uri).query(&query_params);
+  let data: Map<String, Value> = upstream.send_json(request, Upstream::GeoTimeZone).await?;
+  if data.contains_key("abbreviation") {
+    let mut tz_data = TzRow::new(&data);
+    if let Some(geo) = geo_opt {
+      tz_data.calc_solar_offset(geo.lng);
     }
+    return Ok(tz_data);
+  }
+  Err(GeoFinderError::NotFound(format!("no timezone found for {}", opt_str)))
+}
+
+/// IANA zone name for a zone name or location, from the boundary index when loaded or else the GeoTimeZone API
+pub async fn get_zone_name(upstream: &UpstreamClient, tz_index: &TzBoundaryIndex, geo_opt: Option<Geo>, zn_opt: Option<&str>) -> GeoFinderResult<String> {
+  if let Some(zn) = zn_opt {
+    return Ok(zn.to_string());
+  }
+  let geo = geo_opt.ok_or(GeoFinderError::BadInput("a valid zone name (zn) or location (loc) is required".to_string()))?;
+  if let Some(zn) = tz_index.zone_name(geo) {
+    return Ok(zn);
+  }
+  let tz_data = get_tz_data(upstream, tz_index, Some(geo), None, None).await?;
+  Ok(tz_data.zone_name().to_string())
+}
+
+fn get_local_tz_data(tz_index: &TzBoundaryIndex, geo_opt: Option<Geo>, zn_opt: Option<&str>, date_opt: Option<&str>) -> Option<TzRow> {
+  let zone_name = match zn_opt {
+    Some(zn) => zn.to_string(),
+    None => tz_index.zone_name(geo_opt?)?
+  };
+  let ts = date_opt.and_then(timestamp_from_string).unwrap_or(Utc::now().timestamp());
+  let mut tz_data = build_local_tz_row(&zone_name, ts)?;
+  if let Some(geo) = geo_opt {
+    tz_data.calc_solar_offset(geo.lng);
   }
-  None   
+  Some(tz_data)
 }
 
-pub async fn get_place_lookup(search: &str, cc_opt: Option<String>, fuzzy_opt: Option<u32>) -> Option<Vec<PlaceRow>> {
-  let client = reqwest::Client::new();
+/// Searches the local gazetteer as the GeoTimeZone /lookup endpoint would, on the blocking
+/// thread pool since scanning the name index is CPU-bound
+pub async fn search_local_places(gazetteer: SharedGazetteer, search: String, cc_opt: Option<String>, fuzzy_opt: Option<u32>, near_opt: Option<Geo>, limit: usize) -> GeoFinderResult<Vec<PlaceRow>> {
+  if search.len() < 2 {
+    return Err(GeoFinderError::BadInput("the place search must be at least 2 characters".to_string()));
+  }
+  let cc_opt = cc_opt.filter(|cc| cc.len() == 2);
+  let fuzzy = fuzzy_opt.filter(|fz| *fz <= 100).unwrap_or(0);
+  tokio::task::spawn_blocking(move || {
+    gazetteer.search(&search, cc_opt.as_deref(), fuzzy, near_opt, limit).iter()
+      .map(PlaceRow::from_gazetteer)
+      .collect()
+  }).await.map_err(|e| {
+    tracing::error!("place search failed: {}", e);
+    GeoFinderError::StorageFailure("the place search failed".to_string())
+  })
+}
+
+/// Re-orders rows from the GeoTimeZone API by the same blend of name match, population
+/// and proximity as local searches
+pub fn rank_place_rows(rows: Vec<PlaceRow>, search: &str, near_opt: Option<Geo>) -> Vec<PlaceRow> {
+  let mut rows: Vec<PlaceRow> = rows.into_iter().map(|mut row| {
+    let distance = near_opt.map(|near| haversine_km(near.lat, near.lng, row.lat, row.lng));
+    let score = place_score(name_similarity(search, row.name()), row.pop, distance);
+    row.set_rank(score, distance);
+    row
+  }).collect();
+  rows.sort_by(|a, b| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)));
+  rows
+}
+
+pub async fn get_place_lookup(upstream: &UpstreamClient, search: &str, cc_opt: Option<String>, fuzzy_opt: Option<u32>) -> GeoFinderResult<Vec<PlaceRow>> {
   let mut query_params = vec![
     ("place", search),
   ];
@@ -103,24 +200,12 @@ pub async fn get_place_lookup(search: &str, cc_opt: Option<String>, fuzzy_opt: O
       query_params.push(("fuzzy", fuzzy_str.as_str()));
     }
   }
-  if valid {
-    let uri = format!("{}/lookup", get_gtz_url());
-    let result = client.get(&uri)
-      .query(&query_params).send()
-      .await
-      .expect("failed to get response")
-      .text()
-      .await;
-    if let Ok(result_string) = result {
-      let results: Result<Vec<Map<String, Value>>, Error> = serde_json::from_str(&result_string);
-      let mut place_rows: Vec<PlaceRow> = Vec::new();
-      if let Ok(rows) = results {
-        for row in rows {
-          place_rows.push(PlaceRow::new(&row));
-        }
-        return Some(place_rows);
-      }
-    }
+  if !valid {
+    return Err(GeoFinderError::BadInput("the place search must be at least 2 characters".to_string()));
   }
-  None
+  let uri = format!("{}/lookup", get_gtz_url());
+  let request = upstream.get(Upstream::GeoTimeZone, &uri).query(&query_params);
+  let rows: Vec<Map<String, Value>> = upstream.send_json(request, Upstream::GeoTimeZone).await?;
+  let place_rows = rows.iter().map(PlaceRow::new).collect::<Vec<PlaceRow>>();
+  Ok(place_rows)
 }
\ No newline at end of file
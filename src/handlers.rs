@@ -1,131 +1,182 @@
-use std::{thread, time};
+use std::{collections::HashMap, future::Future, thread, time};
 use axum::{
   extract,
   http::StatusCode,
   response::IntoResponse,
   Json
 };
+use futures::{future, stream::{self, StreamExt}};
+use chrono::{DateTime, Utc};
 use mongodb::Client;
-use serde_json::json;
+use serde_json::{json, Value};
 use string_patterns::PatternReplace;
 
 use crate::{
-  addresses::get_remote_addresses, astro::{self, get_astro_data_cached},
-  common::{build_store_key_from_geo, is_valid_date_string, GeoParams, PostParams},
-  fetchers::{fetch_pc_zone, fetch_pc_zones, fetch_pcs, match_pc_zone, update_pc_addresses},
+  addresses::get_remote_addresses, astro::{get_astro_calendar_cached, get_astro_data_cached, MAX_CALENDAR_DAYS},
+  common::{build_store_key_from_geo, LocationSections, get_batch_concurrency, get_section_deadline_ms, is_valid_date_string, normalize_pc_prefix, parse_uk_postcode, BatchParams, GeoParams, PolygonParams, PostParams, TimeConvertParams},
+  errors::{GeoFinderError, GeoFinderResult},
+  gazetteer::{Gazetteer, SharedGazetteer},
+  fetchers::{fetch_pc_lookup, fetch_pc_prefix_matches, fetch_pc_zone, fetch_pc_zones, fetch_pcs, fetch_pcs_within, match_pc_zone, update_pc_addresses},
   geonames::{fetch_poi_cached, fetch_postcodes, fetch_weather_cached, fetch_wiki_entries_cached},
-  geotime::{build_pc_zones_from_geo_info, get_geotz_data, get_place_lookup, get_tz_data},
-  models::{Geo, GeoTimeInfo, LocationInfo, PcZone, PlaceRow, SimplePlace},
-  simple_iso::timestamp_from_string,
-  store::{
-    redis_addresses_have_been_checked, redis_data_have_been_checked, redis_get_geo_nearby, redis_get_pc_results, redis_get_pc_zones, redis_get_place_rows, redis_get_timezone, redis_set_data_checked, redis_set_geo_nearby, redis_set_pc_results, redis_set_pc_zones, redis_set_place_rows, redis_set_timezone
-  }
+  geotime::{build_pc_zones_from_geo_info, get_geotz_data, get_geotz_data_cached, get_place_lookup, rank_place_rows, search_local_places, get_tz_data, get_zone_name},
+  models::{build_feature_collection, Geo, GeoNearby, LocationInfo, PcZone, PlaceRow, SkippedSection},
+  simple_iso::{timestamp_from_string, SimpleISO8601},
+  store::{CacheStore, SharedStore},
+  timezones::{build_local_tz_row, is_known_zone, list_transitions, resolve_local_time, zone_offset_at, LocalTimeMatch, SharedTzIndex, TzBoundaryIndex},
+  upstream::{SharedUpstream, Upstream, UpstreamClient}
 };
 
 
-pub async fn get_nearest_pcs(extract::State(client): extract::State<Client>, query: extract::Query<GeoParams>) -> impl IntoResponse {
-  if let Some(geo) = query.to_geo_opt() {
-    let km = query.km.unwrap_or(10.0);
-    let limit = query.limit.unwrap_or(10);
-    let ck = build_store_key_from_geo("pc", geo, Some(km), Some(limit), 6);
-    let mut rows = redis_get_pc_results(&ck);
-    let mut cached = false;
-    if rows.len() < 1 {
-      rows = fetch_pcs(&client, geo, km, limit).await;
-      if rows.len() > 0 {
-        redis_set_pc_results(&ck, &rows);
-      }
-    } else {
-      cached = true;
+pub async fn get_nearest_pcs(extract::State(client): extract::State<Client>, extract::State(store): extract::State<SharedStore>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let geo = query.require_geo()?;
+  let km = query.km.unwrap_or(10.0);
+  let limit = query.limit.unwrap_or(10);
+  let ck = build_store_key_from_geo("pc", geo, Some(km), Some(limit), 6);
+  let mut rows = store.get_pc_results(&ck).await;
+  let mut cached = false;
+  if rows.len() < 1 {
+    rows = fetch_pcs(&client, geo, km, limit).await?;
+    if rows.len() > 0 {
+      store.set_pc_results(&ck, &rows).await;
     }
-    let response = json!({ "valid": true, "cached": cached, "rows": rows });
-    (StatusCode::OK, Json(response))
   } else {
-    let response = json!({ "valid": false });
-    (StatusCode::NOT_ACCEPTABLE, Json(response))
+    cached = true;
   }
+  if query.wants_geojson() {
+    return Ok(Json(build_feature_collection(&rows, json!({ "cached": cached, "km": km, "distanceUnit": "m" }))));
+  }
+  Ok(Json(json!({ "valid": true, "cached": cached, "rows": rows })))
+}
+
+
+pub async fn get_pcs_in_bounds(extract::State(client): extract::State<Client>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let bbox = query.require_bbox()?;
+  let limit = query.limit.unwrap_or(100);
+  let skip = query.skip.unwrap_or(0);
+  let rows = fetch_pcs_within(&client, vec![bbox.to_ring()], limit, skip).await?;
+  Ok(Json(json!({ "valid": true, "bbox": bbox, "skip": skip, "num": rows.len(), "rows": rows })))
+}
+
+pub async fn get_pcs_in_polygon(extract::State(client): extract::State<Client>, body: extract::Json<PolygonParams>) -> GeoFinderResult<Json<Value>> {
+  let rings = body.to_rings()?;
+  let limit = body.limit.unwrap_or(100);
+  let skip = body.skip.unwrap_or(0);
+  let rows = fetch_pcs_within(&client, rings, limit, skip).await?;
+  Ok(Json(json!({ "valid": true, "skip": skip, "num": rows.len(), "rows": rows })))
 }
 
+/// Forward geocodes a full postcode, sector or outward code to its centroid, extent and member postcodes
+pub async fn get_pc_lookup(extract::State(client): extract::State<Client>, extract::State(store): extract::State<SharedStore>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let pc_str = query.pc.clone().unwrap_or_default();
+  let (code, _) = parse_uk_postcode(&pc_str)
+    .ok_or(GeoFinderError::BadInput(format!("{} is not a UK postcode, sector or outward code", pc_str)))?;
+  let limit = query.limit.unwrap_or(100);
+  let skip = query.skip.unwrap_or(0);
+  let ck = format!("pc_lookup_{}_{}_{}", code.replace(" ", "_"), skip, limit);
+  let mut cached = false;
+  let lookup = if let Some(lookup) = store.get_pc_lookup(&ck).await {
+    cached = true;
+    lookup
+  } else {
+    let lookup = fetch_pc_lookup(&client, &code, limit, skip).await?;
+    store.set_pc_lookup(&ck, &lookup).await;
+    lookup
+  };
+  if query.wants_geojson() {
+    return Ok(Json(build_feature_collection(&lookup.zones, json!({ "code": lookup.code, "type": lookup.match_type, "count": lookup.count, "cached": cached }))));
+  }
+  Ok(Json(json!({ "valid": true, "cached": cached, "skip": skip, "result": lookup })))
+}
 
-pub async fn get_gtz(extract::State(client): extract::State<Client>, query: extract::Query<GeoParams>) -> impl IntoResponse {
-  if let Some(geo) = query.to_geo_opt() {
-    let mut dt_opt: Option<String> = None;
-     // Clone query.dt outside the inner if let block
-     let dt = query.dt.clone();
-     if let Some(ds) = dt {
-         if is_valid_date_string(&ds) {
-             dt_opt = Some(ds); // Assign ds directly, not as a reference
-         }
+/// Type-ahead suggestions for a partial postcode
+pub async fn get_pc_autocomplete(extract::State(client): extract::State<Client>, extract::State(store): extract::State<SharedStore>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let prefix_str = query.pc.clone().unwrap_or_default();
+  let prefix = normalize_pc_prefix(&prefix_str)
+    .ok_or(GeoFinderError::BadInput(format!("{} is not a valid postcode prefix", prefix_str)))?;
+  let limit = query.limit.unwrap_or(10).clamp(1, 50);
+  let ck = format!("pc_prefix_{}_{}", prefix.replace(" ", "_"), limit);
+  let mut cached = false;
+  let rows = if let Some(rows) = store.get_pc_prefix_matches(&ck).await {
+    cached = true;
+    rows
+  } else {
+    let rows = fetch_pc_prefix_matches(&client, &prefix, limit).await?;
+    store.set_pc_prefix_matches(&ck, &rows).await;
+    rows
+  };
+  Ok(Json(json!({ "valid": true, "cached": cached, "prefix": prefix, "rows": rows })))
+}
+
+pub async fn get_gtz(extract::State(client): extract::State<Client>, extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, extract::State(tz_index): extract::State<SharedTzIndex>, extract::State(gazetteer): extract::State<SharedGazetteer>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let geo = query.require_geo()?;
+  let mut dt_opt: Option<String> = None;
+  // Clone query.dt outside the inner if let block
+  let dt = query.dt.clone();
+  if let Some(ds) = dt {
+    if is_valid_date_string(&ds) {
+      dt_opt = Some(ds); // Assign ds directly, not as a reference
     }
-    let ck = build_store_key_from_geo("place", geo, None, None, 5);
-    let mut data: Option<GeoTimeInfo> = None;
-    let geo_data = redis_get_geo_nearby(&ck);
-    if let Some(gdata) = geo_data {
-      let has_zn = gdata.zone_name.is_some();
-      let zn_opt = if has_zn { gdata.zone_name.as_deref() } else { None };
-      let geo_opt = Some(geo);
-      let cache_key = format!("tz_info_{}_{}_{}", zn_opt.unwrap_or(""), geo.to_approx_key(3), dt_opt.clone().unwrap_or("a".to_string()));
-      let mut time_opt = redis_get_timezone(&cache_key);
-      let is_cached = time_opt.is_some();
-      if !is_cached {
-        time_opt =  get_tz_data(geo_opt, zn_opt, dt_opt.clone().as_deref()).await;
-      }
-      if let Some(mut time) = time_opt {
-        if is_cached {
-          let ts_opt = if let Some(dt) = dt_opt.clone() {
-            timestamp_from_string(&dt)
-          } else {
-            None
-          };
-          time.update_time(ts_opt);
-        } else {
-          redis_set_timezone(&cache_key, &time);
-        }
-        let mut info = GeoTimeInfo::new(gdata, time);  
-        info.set_cached();
-        data = Some(info);
-      } else {
-        let mut info = GeoTimeInfo::new_geoplace(gdata);
-        info.set_cached();
-        data = Some(info);
-      }
-    } else {
-      if let Some(gtz_data)= get_geotz_data(&client, geo, dt_opt.as_deref()).await {
-        if let Some(place) = gtz_data.place.clone() {
-          redis_set_geo_nearby(&ck, &place);
-        }
-        data = Some(gtz_data);
+  }
+  let mut data = get_geotz_data_cached(&client, store.as_ref(), upstream.as_ref(), tz_index.as_ref(), gazetteer.as_ref(), geo, dt_opt.as_deref()).await?;
+
+  if let Some(show_astro) = query.astro {
+    if show_astro > 0 {
+      if let Ok(astro) = get_astro_data_cached(store.as_ref(), upstream.as_ref(), geo, dt_opt, query.is_local()).await {
+        data.set_astro(astro);
       }
     }
+  }
+  Ok(Json(json!(data)))
+}
 
-    if let Some(show_astro) = query.astro {
-      if show_astro > 0 {
-        if let Some(info) = data.as_mut() {
-          let astro_opt = get_astro_data_cached(geo, dt_opt).await;
-          if let Some(astro) = astro_opt {
-            info.set_astro(astro);
-          }
+pub async fn get_gtz_batch(extract::State(client): extract::State<Client>, extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, extract::State(tz_index): extract::State<SharedTzIndex>, extract::State(gazetteer): extract::State<SharedGazetteer>, body: extract::Json<BatchParams>) -> GeoFinderResult<Json<Value>> {
+  let points = body.to_points()?;
+  let num = points.len();
+  // near-identical points and repeated dates resolve once
+  let mut unique_keys: HashMap<String, usize> = HashMap::new();
+  let mut unique_points: Vec<(Geo, Option<String>)> = vec![];
+  let mut indices: Vec<usize> = Vec::with_capacity(num);
+  for (geo, dt_opt) in points {
+    let key = format!("{}_{}", geo.to_approx_key(4), dt_opt.clone().unwrap_or_default());
+    let index = *unique_keys.entry(key).or_insert_with(|| {
+      unique_points.push((geo, dt_opt));
+      unique_points.len() - 1
+    });
+    indices.push(index);
+  }
+  let num_unique = unique_points.len();
+  let results: Vec<Value> = stream::iter(unique_points)
+    .map(|(geo, dt_opt)| {
+      let client = client.clone();
+      let store = store.clone();
+      let upstream = upstream.clone();
+      let tz_index = tz_index.clone();
+      let gazetteer = gazetteer.clone();
+      async move {
+        match get_geotz_data_cached(&client, store.as_ref(), upstream.as_ref(), tz_index.as_ref(), gazetteer.as_ref(), geo, dt_opt.as_deref()).await {
+          Ok(info) => json!(info),
+          Err(error) => json!({ "valid": false, "code": error.code(), "message": error.message() })
         }
       }
-    }
-    
-    let response = json!(data);
-    (StatusCode::OK, Json(response))
-  } else {
-    let response = json!({ "valid": false });
-    (StatusCode::NOT_ACCEPTABLE, Json(response))
-  }
+    })
+    .buffered(get_batch_concurrency())
+    .collect()
+    .await;
+  let rows: Vec<Value> = indices.into_iter().map(|index| results[index].clone()).collect();
+  Ok(Json(json!({ "valid": true, "num": num, "numUnique": num_unique, "rows": rows })))
 }
 
 
-pub async fn fetch_and_update_addresses(extract::State(client): extract::State<Client>, query: extract::Json<PostParams>) -> impl IntoResponse {
+pub async fn fetch_and_update_addresses(extract::State(client): extract::State<Client>, extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, query: extract::Json<PostParams>) -> GeoFinderResult<Json<Value>> {
   if let Some(pc) = query.pc.clone() {
     let pc_zone_opt = fetch_pc_zone(&client, &pc).await;
     if let Some(mut pc_zone) = pc_zone_opt {
-      if !pc_zone.has_addresses() {
-        let has_been_checked = redis_addresses_have_been_checked(&pc);
+      // a terminated postcode may have been redirected to its live successor
+      let pc = pc_zone.pc.clone();
+      if !pc_zone.has_addresses() && !pc_zone.is_terminated() {
+        let has_been_checked = store.addresses_have_been_checked(&pc).await;
         if !has_been_checked {
-          let addresses_opt = get_remote_addresses(&pc).await;
+          let addresses_opt = get_remote_addresses(store.as_ref(), upstream.as_ref(), &pc).await;
           if let Some(addresses) = addresses_opt {
             update_pc_addresses(&client, &pc, &addresses).await;
             pc_zone.add_addresses(&addresses);
@@ -133,9 +184,9 @@ pub async fn fetch_and_update_addresses(extract::State(client): extract::State<C
           
         }
       }
-      let response = json!(pc_zone);
-      return (StatusCode::OK, Json(response));
-    } 
+      return Ok(Json(json!(pc_zone)));
+    }
+    return Err(GeoFinderError::NotFound(format!("postcode {} not found", pc)));
   } else if query.has_geo() {
     let mut interval = time::Duration::from_millis(500);
     let km_val = query.km.unwrap_or(2.0);
@@ -154,15 +205,15 @@ pub async fn fetch_and_update_addresses(extract::State(client): extract::State<C
     let lng = query.lng.unwrap_or(0.0);
     if lat > 49.0 && lng < 1.8 && lng > -10.0 {
       let geo = Geo::simple(lat, lng);
-      let mut rows = fetch_pc_zones(&client, geo, km, limit, None).await;
+      let mut rows = fetch_pc_zones(&client, geo, km, limit, None).await?;
       let mut updated = 0;
       let mut counter = 0;
       for pc_zone in rows.iter_mut() {
         if !pc_zone.has_addresses() {
           let pc = pc_zone.pc.clone();
-          let has_been_checked = redis_addresses_have_been_checked(&pc);
+          let has_been_checked = store.addresses_have_been_checked(&pc).await;
           if !has_been_checked {
-            let addresses_opt = get_remote_addresses(&pc).await;
+            let addresses_opt = get_remote_addresses(store.as_ref(), upstream.as_ref(), &pc).await;
             if let Some(addresses) = addresses_opt {
               if addresses.len() > 0 {
                 update_pc_addresses(&client, &pc, &addresses).await;
@@ -180,197 +231,244 @@ pub async fn fetch_and_update_addresses(extract::State(client): extract::State<C
           }
         }
       }
-      let response = json!({"rows": rows, "numUpdated": updated});
-      return (StatusCode::OK, Json(response));
+      return Ok(Json(json!({"rows": rows, "numUpdated": updated})));
     }
   }
-  let response = json!({ "valid": false });
-  (StatusCode::NOT_ACCEPTABLE, Json(response))
+  Err(GeoFinderError::BadInput("either a pc or UK lat and lng coordinates are required".to_string()))
 }
 
-pub async fn get_weather_report(query: extract::Query<GeoParams>) -> impl IntoResponse {
-  let mut response = json!({ "valid": false });
-  let mut status = StatusCode::NOT_ACCEPTABLE;
-  if let Some(geo) = query.to_geo_opt() {
-    let (weather_opt, cached) = fetch_weather_cached(geo).await;
-    status = if weather_opt.is_some() {
-      StatusCode::OK
-    } else {
-      StatusCode::NOT_FOUND
-    };
-    if let Some(weather)=  weather_opt {
-      response = json!({ "valid": true, "cached": cached, "weather": weather });
-    } else {
-      response = json!({ "valid": true, "cached": false });
-    }
-  }
-  (status, Json(response))
+pub async fn get_weather_report(extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let geo = query.require_geo()?;
+  let (weather, cached) = fetch_weather_cached(store.as_ref(), upstream.as_ref(), geo).await?;
+  Ok(Json(json!({ "valid": true, "cached": cached, "weather": weather })))
 }
 
-pub async fn get_places_of_interest(query: extract::Query<GeoParams>) -> impl IntoResponse {
-  let mut response = json!({ "valid": false });
-  let mut status = StatusCode::NOT_ACCEPTABLE;
-  if let Some(geo) = query.to_geo_opt() {
-    let (poi_opt, cached) = fetch_poi_cached(geo).await;
-    status = if poi_opt.is_some() { 
-      StatusCode::OK
-    } else {
-      StatusCode::NOT_FOUND
-    };
-    if let Some(poi) = poi_opt {
-      response = json!({ "valid": true, "cached": cached, "items": poi });
-    }
-    
+pub async fn get_places_of_interest(extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let geo = query.require_geo()?;
+  let (poi, cached) = fetch_poi_cached(store.as_ref(), upstream.as_ref(), geo).await?;
+  if query.wants_geojson() {
+    return Ok(Json(build_feature_collection(&poi, json!({ "cached": cached, "distanceUnit": "km" }))));
   }
-  (status, Json(response))
+  Ok(Json(json!({ "valid": true, "cached": cached, "items": poi })))
 }
 
-pub async fn get_nearby_wiki_summaries(query: extract::Query<GeoParams>) -> impl IntoResponse {
-  let mut response = json!({ "valid": false });
-  let mut status = StatusCode::NOT_ACCEPTABLE;
-  if let Some(geo) = query.to_geo_opt() {
-    let (items_opt, cached) = fetch_wiki_entries_cached(geo).await;
-    status = if items_opt.is_some() {
-      StatusCode::OK
-    } else {
-      StatusCode::NOT_FOUND
-    };
-    if let Some(items) = items_opt {
-      response = json!({ "valid": true, "cached": cached, "items": items });
-    }
+pub async fn get_nearby_wiki_summaries(extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let geo = query.require_geo()?;
+  let (items, cached) = fetch_wiki_entries_cached(store.as_ref(), upstream.as_ref(), geo).await?;
+  if query.wants_geojson() {
+    return Ok(Json(build_feature_collection(&items, json!({ "cached": cached, "distanceUnit": "km" }))));
   }
-  (status, Json(response))
+  Ok(Json(json!({ "valid": true, "cached": cached, "items": items })))
 }
 
-pub async fn build_location_info(client: &Client, lat: f64, lng: f64) -> LocationInfo {
-    let geo = Geo::new(lat, lng, 20.0);
-    let ck = build_store_key_from_geo("place", geo, None, None, 5);
-    let mut pn = "".to_string();
-    let mut geo_data = redis_get_geo_nearby(&ck);
-    let mut places: Vec<SimplePlace> = vec![];
-    let mut states: Vec<SimplePlace> = vec![];
-    let mut is_uk = false;
-    let mut is_near_pop_land = false;
-    if geo_data.is_none() {
-      if let Some(gtz_data)= get_geotz_data(&client, geo, None).await {
-        if let Some(place) = gtz_data.place.clone() {
-          redis_set_geo_nearby(&ck, &place);
-          geo_data = Some(place);
-        }
-      }
-    }
-    if let Some(geo_item) = geo_data.clone() {
-      places = geo_item.to_places();
-      states = geo_item.to_states();
-      is_near_pop_land = geo_item.is_near_populated_land();
-      pn = geo_item.name.clone();
-      if let Some(cc) = geo_item.cc {
-        is_uk = cc.starts_with("GB") || cc.starts_with("UK");
+/// Keeps the value of a supplementary section, recording upstream failures as skipped.
+/// Not-found results simply leave the section empty.
+fn section_or_skip<T>(result: GeoFinderResult<T>, section: &str, skipped: &mut Vec<SkippedSection>) -> Option<T> {
+  match result {
+    Ok(value) => Some(value),
+    Err(error) => {
+      if let Some(service) = error.service() {
+        skipped.push(SkippedSection::new(section, service, error.code()));
       }
+      None
     }
+  }
+}
+
+/// Runs a section lookup against its deadline, reporting a miss as a timeout of the given upstream
+async fn within_deadline<T>(future: impl Future<Output = GeoFinderResult<T>>, service: Upstream) -> GeoFinderResult<T> {
+  let deadline = time::Duration::from_millis(get_section_deadline_ms());
+  tokio::time::timeout(deadline, future).await
+    .unwrap_or_else(|_| Err(GeoFinderError::UpstreamTimeout(service.name().to_string())))
+}
+
+/// Awaits the section lookup only when the section was requested
+async fn optional_section<T>(requested: bool, future: impl Future<Output = T>) -> Option<T> {
+  if requested {
+    Some(future.await)
+  } else {
+    None
+  }
+}
+
+/// Nearest postcode zones for a located place. UK zones come from the zones collection with the
+/// nearest address backfilled on demand, elsewhere GeoNames postcodes are checked once a month.
+async fn build_location_zones(client: &Client, store: &dyn CacheStore, upstream: &UpstreamClient, geo: Geo, geo_data: Option<&GeoNearby>, skipped: &mut Vec<SkippedSection>) -> Vec<PcZone> {
+    let pn = geo_data.map(|place| place.name.clone()).unwrap_or_default();
+    let is_uk = geo_data.and_then(|place| place.cc.as_ref()).map(|cc| cc.starts_with("GB") || cc.starts_with("UK")).unwrap_or(false);
+    let is_near_pop_land = geo_data.map(|place| place.is_near_populated_land()).unwrap_or(false);
     let limit = 7;
     let km = 15.0;
     let ck = build_store_key_from_geo("pzones", geo, Some(km), Some(limit), 7);
-    let mut rows: Vec<PcZone> = redis_get_pc_zones(&ck);
+    let mut rows: Vec<PcZone> = store.get_pc_zones(&ck).await;
     let mut pc_cache_set = false;
     if rows.len() < 1 {
       if is_uk {
-        rows = fetch_pc_zones(&client, geo, km, limit, None).await;
+        rows = fetch_pc_zones(&client, geo, km, limit, None).await.unwrap_or_default();
         if rows.len() > 0 {
-          redis_set_pc_zones(&ck, &rows);
+          store.set_pc_zones(&ck, &rows).await;
         }
         if let Some(first) = rows.get_mut(0) {
           first.add_pn(&pn);
           if !first.has_addresses() {
             let pc = first.pc.as_str();
-            let has_been_checked = redis_addresses_have_been_checked(pc);
+            let has_been_checked = store.addresses_have_been_checked(pc).await;
             if !has_been_checked {
-              let addresses_opt = get_remote_addresses(pc).await;
+              let addresses_opt = within_deadline(async { Ok(get_remote_addresses(store, upstream, pc).await) }, Upstream::Addresses).await.ok().flatten();
               if let Some(addresses) = addresses_opt {
                 update_pc_addresses(&client, pc, &addresses).await;
                 first.add_addresses(&addresses);
-                redis_set_pc_zones(&ck, &rows);
+                store.set_pc_zones(&ck, &rows).await;
                 pc_cache_set = true;
               }
             }
           }
           if !pc_cache_set {
-            redis_set_pc_zones(&ck, &rows);
+            store.set_pc_zones(&ck, &rows).await;
           }
         }
       } else {
         if is_near_pop_land {
           let check_key = build_store_key_from_geo("gn_pc_checked_", geo, None, None,7);
-          let has_been_checked = redis_data_have_been_checked(&check_key);
+          let has_been_checked = store.data_have_been_checked(&check_key).await;
           if !has_been_checked {
-            if let Some(matched_rows) = fetch_postcodes(geo).await {
+            if let Some(matched_rows) = section_or_skip(within_deadline(fetch_postcodes(upstream, geo), Upstream::GeoNames).await, "surrounding", skipped) {
               rows = matched_rows;
-              redis_set_data_checked(&check_key, 30);
-              redis_set_pc_zones(&ck, &rows);
+              store.set_data_checked(&check_key, 30).await;
+              store.set_pc_zones(&ck, &rows).await;
             }
           }
         }
       }
     }
     if rows.len() < 1 {
-      if let Some(geo) = geo_data.as_ref() {
-        rows = build_pc_zones_from_geo_info(&geo.clone());
+      if let Some(geo) = geo_data {
+        rows = build_pc_zones_from_geo_info(geo);
       }
     }
-    let (weather, _weather_cached) = fetch_weather_cached(geo).await;
-    let (poi_opt, _poi_cached) = fetch_poi_cached(geo).await;
-    let poi = poi_opt.unwrap_or(vec![]);
-    let (wiki_items_opt, _wiki_cached) = fetch_wiki_entries_cached(geo).await;
-    let wikipedia = wiki_items_opt.unwrap_or(vec![]);
     if is_uk && rows.len() > 0 {
       rows = rows.iter_mut().map(|row| row.clean_addresses()).collect();
     }
-    LocationInfo::new(rows, places, states, weather, poi, wikipedia)
+    rows
 }
 
-pub async fn get_geo_data(extract::State(client): extract::State<Client>, query: extract::Json<PostParams>) -> impl IntoResponse {
-  if let Some(lat) = query.lat {
-    let lng = query.lng.unwrap_or(0.0);
-    let result = build_location_info(&client, lat, lng).await;
-    let response = json!(result);
-    return (StatusCode::OK, Json(response));
+/// The place and its postcode zones resolve in sequence while weather, POIs and Wikipedia
+/// are fetched alongside. Sections that fail or miss their deadline are left empty and reported as skipped.
+/// Only the requested sections call their upstream services.
+pub async fn build_location_info(client: &Client, store: &dyn CacheStore, upstream: &UpstreamClient, tz_index: &TzBoundaryIndex, gazetteer: &Gazetteer, geo: Geo, sections: LocationSections) -> LocationInfo {
+    let place_and_zones = async {
+      let mut skipped: Vec<SkippedSection> = vec![];
+      let ck = build_store_key_from_geo("place", geo, None, None, 5);
+      let mut geo_data = None;
+      if sections.needs_place() {
+        geo_data = store.get_geo_nearby(&ck).await;
+      }
+      if sections.needs_place() && geo_data.is_none() {
+        let gtz_result = within_deadline(get_geotz_data(client, store, upstream, tz_index, gazetteer, geo, None), Upstream::GeoTimeZone).await;
+        if let Some(gtz_data) = section_or_skip(gtz_result, "places", &mut skipped) {
+          if let Some(place) = gtz_data.place.clone() {
+            store.set_geo_nearby(&ck, &place).await;
+            geo_data = Some(place);
+          }
+        }
+      }
+      let rows = if sections.zones {
+        build_location_zones(client, store, upstream, geo, geo_data.as_ref(), &mut skipped).await
+      } else {
+        vec![]
+      };
+      (geo_data, rows, skipped)
+    };
+    // supplementary sections never fail the whole response
+    let ((geo_data, rows, mut skipped), weather_result, poi_result, wiki_result) = tokio::join!(
+      place_and_zones,
+      optional_section(sections.weather, within_deadline(fetch_weather_cached(store, upstream, geo), Upstream::GeoNames)),
+      optional_section(sections.poi, within_deadline(fetch_poi_cached(store, upstream, geo), Upstream::GeoNames)),
+      optional_section(sections.wikipedia, within_deadline(fetch_wiki_entries_cached(store, upstream, geo), Upstream::GeoNames))
+    );
+    let weather = weather_result.and_then(|result| section_or_skip(result, "weather", &mut skipped)).map(|(weather, _cached)| weather);
+    let poi = poi_result.and_then(|result| section_or_skip(result, "poi", &mut skipped)).map(|(poi, _cached)| poi).unwrap_or(vec![]);
+    let wikipedia = wiki_result.and_then(|result| section_or_skip(result, "wikipedia", &mut skipped)).map(|(items, _cached)| items).unwrap_or(vec![]);
+    let places = geo_data.as_ref().filter(|_| sections.places).map(|geo_item| geo_item.to_places()).unwrap_or_default();
+    let states = geo_data.as_ref().filter(|_| sections.states).map(|geo_item| geo_item.to_states()).unwrap_or_default();
+    // only supplementary sections were requested when there is no place or zone to resolve
+    let matched = geo_data.is_some() || !rows.is_empty() || !sections.needs_place();
+    let mut info = LocationInfo::new(matched, rows, places, states, weather, poi, wikipedia);
+    info.set_skipped(skipped);
+    info
+}
+
+pub async fn get_geo_data(extract::State(client): extract::State<Client>, extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, extract::State(tz_index): extract::State<SharedTzIndex>, extract::State(gazetteer): extract::State<SharedGazetteer>, query: extract::Json<PostParams>) -> GeoFinderResult<Json<Value>> {
+  let lat = query.lat.ok_or(GeoFinderError::BadInput("lat is required".to_string()))?;
+  let lng = query.lng.unwrap_or(0.0);
+  let sections = query.to_sections()?;
+  let result = build_location_info(&client, store.as_ref(), upstream.as_ref(), tz_index.as_ref(), gazetteer.as_ref(), Geo::new(lat, lng, 20.0), sections).await;
+  if query.wants_geojson() {
+    return Ok(Json(result.to_feature_collection()));
   }
-  let response = json!({ "valid": false });
-  (StatusCode::NOT_ACCEPTABLE, Json(response))
+  Ok(Json(json!(result)))
 }
 
-pub async fn get_geo_data_by_pc(extract::State(client): extract::State<Client>, query: extract::Json<PostParams>) -> impl IntoResponse {
-  if let Some(pc) = query.pc.clone() {
-    if let Some(pc_zone) = match_pc_zone(&client, &pc).await {
-      let result = build_location_info(&client, pc_zone.lat, pc_zone.lng).await;
-      let response = json!(result);
-      return (StatusCode::OK, Json(response));
-    }
+pub async fn get_geo_data_by_pc(extract::State(client): extract::State<Client>, extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, extract::State(tz_index): extract::State<SharedTzIndex>, extract::State(gazetteer): extract::State<SharedGazetteer>, query: extract::Json<PostParams>) -> GeoFinderResult<Json<Value>> {
+  let pc = query.pc.clone().ok_or(GeoFinderError::BadInput("pc is required".to_string()))?;
+  let sections = query.to_sections()?;
+  let pc_zone = match_pc_zone(&client, store.as_ref(), &pc).await
+    .ok_or(GeoFinderError::NotFound(format!("postcode {} not found", pc)))?;
+  // a terminated postcode without coordinates or a live successor has no location to describe
+  if !pc_zone.has_geo() {
+    return Ok(Json(json!({ "valid": false, "matched": false, "terminated": pc_zone.terminated, "zone": pc_zone })));
+  }
+  let result = build_location_info(&client, store.as_ref(), upstream.as_ref(), tz_index.as_ref(), gazetteer.as_ref(), Geo::new(pc_zone.lat, pc_zone.lng, 20.0), sections).await;
+  if query.wants_geojson() {
+    return Ok(Json(result.to_feature_collection()));
   }
-  let response = json!({ "valid": false });
-  (StatusCode::NOT_ACCEPTABLE, Json(response))
+  let mut response = json!(result);
+  if let Some(redirect) = pc_zone.redirected_from {
+    response["redirectedFrom"] = json!(redirect);
+  } else if pc_zone.is_terminated() {
+    response["terminated"] = json!(pc_zone.terminated);
+  }
+  Ok(Json(response))
 }
 
-/* pub async fn read_pc_zone_updates(extract::State(client): extract::State<Client>, query: extract::Query<GeoParams>) -> impl IntoResponse {
-  let num_updated = get_update_lines(&client).await;
-  let response = json!({ "valid": false, "num_updated": num_updated });
-  (StatusCode::OK, Json(response))
-} */
+pub async fn show_astro_data(extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let geo = query.require_geo()?;
+  let astro = get_astro_data_cached(store.as_ref(), upstream.as_ref(), geo, query.dt.clone(), query.is_local()).await?;
+  Ok(Json(json!({ "valid": true, "astro": astro })))
+}
 
-pub async fn show_astro_data(query: extract::Query<GeoParams>) -> impl IntoResponse {
-  let mut status = StatusCode::NOT_ACCEPTABLE;
-  let mut response = json!({ "valid": false });
-  if let Some(geo) = query.to_geo_opt() {
-    let astro_opt = get_astro_data_cached(geo, query.dt.clone()).await;
-    if let Some(astro) = astro_opt {
-      response = json!({ "valid": true, "astro": astro });
-      status = StatusCode::OK;
+/// Sunrise, sunset, day length, moon phase and moonrise/moonset for each local day from start
+/// to end inclusive, defaulting to 30 days from today
+pub async fn show_astro_calendar(extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, extract::State(tz_index): extract::State<SharedTzIndex>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let geo = query.require_geo()?;
+  let zone_name = get_zone_name(upstream.as_ref(), tz_index.as_ref(), Some(geo), query.zn.as_deref()).await?;
+  if !is_known_zone(&zone_name) {
+    return Err(GeoFinderError::BadInput(format!("{} is not a known timezone", zone_name)));
+  }
+  let day_secs = 24 * 60 * 60;
+  let start_date = match query.start.as_deref() {
+    Some(start) => timestamp_from_string(start).ok_or(GeoFinderError::BadInput(format!("invalid start date {}", start)))?,
+    // today's date where the location is, not in UTC
+    None => {
+      let now = Utc::now().timestamp();
+      now + zone_offset_at(&zone_name, now).map(|current| current.offset).unwrap_or(0)
     }
+  }.div_euclid(day_secs) * day_secs;
+  let end_date = match query.end.as_deref() {
+    Some(end) => timestamp_from_string(end).ok_or(GeoFinderError::BadInput(format!("invalid end date {}", end)))?.div_euclid(day_secs) * day_secs,
+    None => start_date + 29 * day_secs
+  };
+  let num_days = (end_date - start_date) / day_secs + 1;
+  if !(1..=MAX_CALENDAR_DAYS).contains(&num_days) {
+    return Err(GeoFinderError::BadInput(format!("the date range must cover between 1 and {} days", MAX_CALENDAR_DAYS)));
   }
-  (status, Json(response))
+  let days = get_astro_calendar_cached(store.as_ref(), geo, &zone_name, start_date, num_days).await?;
+  Ok(Json(json!({ "valid": true, "zoneName": zone_name, "num": days.len(), "days": days })))
 }
 
-pub async fn show_place_lookup(query: extract::Query<GeoParams>) -> impl IntoResponse {
+/// Place name search against the local gazetteer when loaded, otherwise the GeoTimeZone API.
+/// Results are ranked by name match and population, and by proximity to near=lat,lng if given.
+pub async fn show_place_lookup(extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, extract::State(gazetteer): extract::State<SharedGazetteer>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
   let search = if let Some(place_str) = query.place.clone() {
     place_str
   } else if let Some(search_str) = query.search.clone() {
@@ -381,7 +479,10 @@ pub async fn show_place_lookup(query: extract::Query<GeoParams>) -> impl IntoRes
   let fuzzy_opt = query.fuzzy;
   let cc_opt = query.cc.clone();
   let mut response = json!([]);
-  if search.len() > 1 {
+  if search.len() > 1 && gazetteer.is_loaded() {
+    let limit = query.limit.unwrap_or(20).clamp(1, 100) as usize;
+    response = json!(search_local_places(gazetteer.clone(), search, cc_opt, fuzzy_opt, query.to_near_opt(), limit).await?);
+  } else if search.len() > 1 {
     let mut key_parts = vec!["lookup".to_string(), search.to_lowercase().pattern_replace_ci(r#"\s+"#, "_")];
     if let Some(cc) = cc_opt.clone() {
       key_parts.push(cc);
@@ -390,63 +491,136 @@ pub async fn show_place_lookup(query: extract::Query<GeoParams>) -> impl IntoRes
       key_parts.push(fz.to_string());
     }
     let cache_key = key_parts.join("_");
-    let rows_opt = redis_get_place_rows(&cache_key);
-    let is_cached = rows_opt.is_some();
-    let mut has_uncached_results = false;
-    let mut rows: Vec<PlaceRow> = Vec::new();
-    if !is_cached {
-      if let Some(results ) = get_place_lookup(&search, cc_opt, fuzzy_opt).await {
-        rows = results;
-        has_uncached_results = true;
-      }
+    let rows: Vec<PlaceRow> = if let Some(c_rows) = store.get_place_rows(&cache_key).await {
+      c_rows
     } else {
-      if let Some(c_rows) = rows_opt {
-        rows = c_rows;
-      }
-    }
-    if has_uncached_results {
-      redis_set_place_rows(&cache_key, &rows);
-    }
-    response = json!(rows);
+      let results = get_place_lookup(upstream.as_ref(), &search, cc_opt, fuzzy_opt).await?;
+      store.set_place_rows(&cache_key, &results).await;
+      results
+    };
+    response = json!(rank_place_rows(rows, &search, query.to_near_opt()));
   }
-  (StatusCode::OK, Json(response))
+  Ok(Json(response))
 }
 
-pub async fn show_timezone(query: extract::Query<GeoParams>) -> impl IntoResponse {
-  let mut status = StatusCode::NOT_ACCEPTABLE;
-  let mut response = json!({"valid": false });
-  if let Some(geo) = query.to_geo_opt() {
-    let mut dt_opt: Option<String> = None;
-     // Clone query.dt outside the inner if let block
-     let dt = query.dt.clone();
-     if let Some(ds) = dt {
-         if is_valid_date_string(&ds) {
-             dt_opt = Some(ds); // Assign ds directly, not as a reference
-         }
-    }
-    let zn_opt = query.zn.clone();
-    let zn_key = zn_opt.clone().unwrap_or("".to_owned());
-    let geo_opt = Some(geo);
-    let cache_key = format!("tz_info_{}_{}_{}", zn_key, geo.to_approx_key(3), dt_opt.clone().unwrap_or("a".to_string()));
-    let mut time_opt = redis_get_timezone(&cache_key);
-    let is_cached = time_opt.is_some();
-    if !is_cached {
-      time_opt =  get_tz_data(geo_opt, zn_opt.as_deref(), dt_opt.clone().as_deref()).await;
+pub async fn show_timezone(extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>, extract::State(tz_index): extract::State<SharedTzIndex>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let geo = query.require_geo()?;
+  let mut dt_opt: Option<String> = None;
+  // Clone query.dt outside the inner if let block
+  let dt = query.dt.clone();
+  if let Some(ds) = dt {
+    if is_valid_date_string(&ds) {
+      dt_opt = Some(ds); // Assign ds directly, not as a reference
     }
-    if let Some(mut time) = time_opt {
-      if is_cached {
-        let ts_opt = if let Some(dt) = dt_opt.clone() {
-          timestamp_from_string(&dt)
-        } else {
-          None
-        };
-        time.update_time(ts_opt);
-      } else {
-        redis_set_timezone(&cache_key, &time);
+  }
+  let zn_opt = query.zn.clone();
+  let zn_key = zn_opt.clone().unwrap_or("".to_owned());
+  let geo_opt = Some(geo);
+  let cache_key = format!("tz_info_{}_{}_{}", zn_key, geo.to_approx_key(3), dt_opt.clone().unwrap_or("a".to_string()));
+  let time = if let Some(mut time) = store.get_timezone(&cache_key).await {
+    let ts_opt = if let Some(dt) = dt_opt.clone() {
+      timestamp_from_string(&dt)
+    } else {
+      None
+    };
+    time.update_time(ts_opt);
+    time
+  } else {
+    let time = get_tz_data(upstream.as_ref(), tz_index.as_ref(), geo_opt, zn_opt.as_deref(), dt_opt.clone().as_deref()).await?;
+    store.set_timezone(&cache_key, &time).await;
+    time
+  };
+  Ok(Json(json!(time)))
+}
+
+/// Every offset or abbreviation change for a zone name or location between start and end dates,
+/// defaulting to one year from now
+pub async fn show_tz_transitions(extract::State(upstream): extract::State<SharedUpstream>, extract::State(tz_index): extract::State<SharedTzIndex>, query: extract::Query<GeoParams>) -> GeoFinderResult<Json<Value>> {
+  let geo_opt = if query.zn.is_none() { Some(query.require_geo()?) } else { None };
+  let zone_name = get_zone_name(upstream.as_ref(), tz_index.as_ref(), geo_opt, query.zn.as_deref()).await?;
+  if !is_known_zone(&zone_name) {
+    return Err(GeoFinderError::BadInput(format!("{} is not a known timezone", zone_name)));
+  }
+  let start_ts = match query.start.as_deref() {
+    Some(start) => timestamp_from_string(start).ok_or(GeoFinderError::BadInput(format!("invalid start date {}", start)))?,
+    None => Utc::now().timestamp()
+  };
+  let end_ts = match query.end.as_deref() {
+    Some(end) => timestamp_from_string(end).ok_or(GeoFinderError::BadInput(format!("invalid end date {}", end)))?,
+    None => start_ts + 366 * 24 * 60 * 60
+  };
+  if end_ts <= start_ts {
+    return Err(GeoFinderError::BadInput("end must be after start".to_string()));
+  }
+  if end_ts - start_ts > 100 * 366 * 24 * 60 * 60 {
+    return Err(GeoFinderError::BadInput("the date range may not exceed 100 years".to_string()));
+  }
+  let transitions = list_transitions(&zone_name, start_ts, end_ts).unwrap_or_default();
+  Ok(Json(json!({ "valid": true, "zoneName": zone_name, "start": start_ts, "end": end_ts, "num": transitions.len(), "transitions": transitions })))
+}
+
+/// Converts a local time at the source to UTC and to the local time at each target
+pub async fn convert_time(extract::State(upstream): extract::State<SharedUpstream>, extract::State(tz_index): extract::State<SharedTzIndex>, body: extract::Json<TimeConvertParams>) -> GeoFinderResult<Json<Value>> {
+  body.validate()?;
+  let source = &body.source;
+  let zone_name = get_zone_name(upstream.as_ref(), tz_index.as_ref(), source.to_geo_opt(), source.zn.as_deref()).await?;
+  let local_ts = timestamp_from_string(&body.dt).ok_or(GeoFinderError::BadInput(format!("invalid local date-time {}", body.dt)))?;
+  let matched = resolve_local_time(&zone_name, local_ts)
+    .ok_or(GeoFinderError::BadInput(format!("{} is not a known timezone", zone_name)))?;
+  let utc_ts = matched.utc_ts();
+  let utc = DateTime::from_timestamp(utc_ts, 0).map(|dt| dt.to_simple_iso()).unwrap_or_default();
+  let mut source_time = build_local_tz_row(&zone_name, utc_ts)
+    .ok_or(GeoFinderError::BadInput(format!("{} is not a known timezone", zone_name)))?;
+  if let Some(geo) = source.to_geo_opt() {
+    source_time.calc_solar_offset(geo.lng);
+  }
+  let targets: Vec<Value> = future::join_all(body.targets.iter().map(|target| {
+    let upstream = upstream.clone();
+    let tz_index = tz_index.clone();
+    let utc = utc.clone();
+    async move {
+      // named zones only need tzdb, coordinates may need the boundary index or GeoTimeZone API
+      let result = match target.zn.as_deref() {
+        Some(zn) => build_local_tz_row(zn, utc_ts).map(|mut time| {
+          if let Some(geo) = target.to_geo_opt() {
+            time.calc_solar_offset(geo.lng);
+          }
+          time
+        }).ok_or(GeoFinderError::BadInput(format!("{} is not a known timezone", zn))),
+        None => get_tz_data(upstream.as_ref(), tz_index.as_ref(), target.to_geo_opt(), None, Some(&utc)).await.map(|mut time| {
+          time.update_time(Some(utc_ts));
+          time
+        })
+      };
+      match result {
+        Ok(time) => json!(time),
+        Err(error) => json!({ "valid": false, "code": error.code(), "message": error.message() })
       }
-      status = StatusCode::OK;
-      response = json!(time);
     }
-  }
+  })).await;
+  let alternative_utc = match matched {
+    LocalTimeMatch::Ambiguous(_, later) => DateTime::from_timestamp(later, 0).map(|dt| dt.to_simple_iso()),
+    _ => None
+  };
+  Ok(Json(json!({
+    "valid": true,
+    "utc": utc,
+    "ambiguous": matches!(matched, LocalTimeMatch::Ambiguous(..)),
+    "nonexistent": matches!(matched, LocalTimeMatch::Nonexistent(_)),
+    "alternativeUtc": alternative_utc,
+    "source": source_time,
+    "targets": targets
+  })))
+}
+
+pub async fn show_health(extract::State(store): extract::State<SharedStore>, extract::State(upstream): extract::State<SharedUpstream>) -> impl IntoResponse {
+  let health = store.health().await;
+  let status = if health.connected {
+    StatusCode::OK
+  } else {
+    StatusCode::SERVICE_UNAVAILABLE
+  };
+  // open breakers degrade responses but do not make this instance unhealthy
+  let response = json!({ "valid": health.connected, "store": health, "upstreams": upstream.breaker_statuses() });
   (status, Json(response))
-}
\ No newline at end of file
+}
@@ -0,0 +1,407 @@
+use std::{fs::{self, File}, io::{BufRead, BufReader}, path::Path};
+use bson::{doc, DateTime, Document};
+use futures::stream::{self, StreamExt};
+use mongodb::{options::UpdateOptions, Client, Collection};
+use serde::{Deserialize, Serialize};
+
+use crate::{common::{get_db_name, get_zones_geo_field, parse_uk_postcode}, fetchers::{fetch_live_pcs_near, SUCCESSOR_KM}, models::{Geo, PcMatchType}};
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// Concurrent upserts per batch
+const UPSERT_CONCURRENCY: usize = 16;
+
+/// Nearest live postcodes recorded as successors of a terminated postcode
+const MAX_SUCCESSORS: u32 = 3;
+
+/// Options for `geofinder import-postcodes <file> [--restart] [--batch-size N]`
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+  pub path: String,
+  pub restart: bool,
+  pub batch_size: usize,
+}
+
+impl ImportOptions {
+  pub fn from_args(args: &[String]) -> Result<Self, String> {
+    let mut path: Option<String> = None;
+    let mut restart = false;
+    let mut batch_size = DEFAULT_BATCH_SIZE;
+    let mut items = args.iter();
+    while let Some(arg) = items.next() {
+      match arg.as_str() {
+        "--restart" => restart = true,
+        "--batch-size" => {
+          batch_size = items.next().and_then(|v| v.parse::<usize>().ok()).filter(|v| *v > 0)
+            .ok_or("--batch-size must be a positive number".to_string())?;
+        },
+        _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+        _ => path = Some(arg.to_owned())
+      }
+    }
+    let path = path.ok_or("usage: geofinder import-postcodes <file.csv|file.tsv> [--restart] [--batch-size N]".to_string())?;
+    Ok(ImportOptions {
+      path,
+      restart,
+      batch_size
+    })
+  }
+
+  fn progress_path(&self) -> String {
+    format!("{}.progress", self.path)
+  }
+}
+
+/// Running totals, saved after each batch so an interrupted import resumes where it stopped.
+/// `lines` only advances past batches in which every upsert succeeded, so a resumed import retries failed rows.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ImportCounts {
+  pub lines: usize,
+  pub inserted: usize,
+  pub updated: usize,
+  pub skipped: usize,
+  #[serde(default)]
+  pub failed: usize,
+  #[serde(default)]
+  pub terminated: usize,
+  #[serde(default)]
+  pub successors: usize,
+}
+
+/// Header names accepted for each zones field, compared in lower case without spaces or punctuation.
+/// The first list covers the ONS Postcode Directory, later names cover simpler CSV exports.
+const COLUMN_ALIASES: [(&str, &[&str]); 15] = [
+  ("pc", &["pcds", "pc", "postcode"]),
+  ("lat", &["lat", "latitude"]),
+  ("lng", &["long", "lng", "longitude"]),
+  ("e", &["oseast1m", "e", "easting"]),
+  ("n", &["osnrth1m", "n", "northing"]),
+  ("gr", &["gr", "gridref"]),
+  ("w", &["w", "ward", "wardname"]),
+  ("wc", &["osward", "wc", "wardcode"]),
+  ("d", &["d", "district", "districtname", "ladname"]),
+  ("cy", &["cy", "county", "countyname"]),
+  ("c", &["ctry", "c", "country"]),
+  ("lc", &["lc", "locality", "builtuparea", "parish"]),
+  ("terminated", &["doterm", "terminated", "dateterminated"]),
+  ("in_use", &["inuse"]),
+  ("successors", &["successors", "successor", "replacedby", "newpostcode"]),
+];
+
+/// ONS country codes as they appear in the ctry column
+const COUNTRY_CODES: [(&str, &str); 6] = [
+  ("E92000001", "England"),
+  ("W92000004", "Wales"),
+  ("S92000003", "Scotland"),
+  ("N92000002", "Northern Ireland"),
+  ("L93000001", "Channel Islands"),
+  ("M83000003", "Isle of Man"),
+];
+
+fn header_key(name: &str) -> String {
+  name.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+/// Column index of each recognised field
+struct ColumnMap {
+  columns: Vec<(&'static str, usize)>,
+}
+
+impl ColumnMap {
+  fn new(headers: &[String]) -> Result<Self, String> {
+    let keys: Vec<String> = headers.iter().map(|h| header_key(h)).collect();
+    let columns: Vec<(&'static str, usize)> = COLUMN_ALIASES.iter().filter_map(|(field, aliases)| {
+      aliases.iter().find_map(|alias| keys.iter().position(|key| key == alias)).map(|index| (*field, index))
+    }).collect();
+    let map = ColumnMap { columns };
+    for required in ["pc", "lat", "lng"] {
+      if map.index(required).is_none() {
+        return Err(format!("no {} column found in the header", required));
+      }
+    }
+    Ok(map)
+  }
+
+  fn index(&self, field: &str) -> Option<usize> {
+    self.columns.iter().find(|(name, _)| *name == field).map(|(_, index)| *index)
+  }
+
+  fn get<'a>(&self, cells: &'a [String], field: &str) -> Option<&'a str> {
+    self.index(field).and_then(|index| cells.get(index)).map(|v| v.trim()).filter(|v| !v.is_empty())
+  }
+}
+
+/// Splits a CSV line, allowing quoted cells with doubled quotes, or a TSV line on tabs
+fn split_line(line: &str, tsv: bool) -> Vec<String> {
+  if tsv {
+    return line.split('\t').map(|cell| cell.to_string()).collect();
+  }
+  let mut cells: Vec<String> = vec![];
+  let mut cell = String::new();
+  let mut quoted = false;
+  let mut chars = line.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '"' if quoted && chars.peek() == Some(&'"') => {
+        cell.push('"');
+        chars.next();
+      },
+      '"' => quoted = !quoted,
+      ',' if !quoted => cells.push(std::mem::take(&mut cell)),
+      _ => cell.push(c)
+    }
+  }
+  cells.push(cell);
+  cells
+}
+
+/// 10-figure Ordnance Survey grid reference, e.g. TQ3010680245. Northern Ireland uses the Irish grid.
+pub fn os_grid_ref(easting: f64, northing: f64) -> Option<String> {
+  if !(0.0..700000.0).contains(&easting) || !(0.0..1300000.0).contains(&northing) {
+    return None;
+  }
+  let (e, n) = (easting as u32, northing as u32);
+  let (e100k, n100k) = (e / 100000, n / 100000);
+  let mut l1 = (19 - n100k) - (19 - n100k) % 5 + (e100k + 10) / 5;
+  let mut l2 = (19 - n100k) * 5 % 25 + e100k % 5;
+  // the grid letters skip I
+  if l1 > 7 {
+    l1 += 1;
+  }
+  if l2 > 7 {
+    l2 += 1;
+  }
+  let letter = |index: u32| char::from(b'A' + index as u8);
+  Some(format!("{}{}{:05}{:05}", letter(l1), letter(l2), e % 100000, n % 100000))
+}
+
+/// Fields to set on the zone for a data row, or None if the row should be skipped.
+/// Terminated postcodes are kept with their termination date and any listed successors,
+/// even without coordinates.
+fn build_zone_values(cells: &[String], map: &ColumnMap) -> Option<(String, Document)> {
+  let (pc, _) = parse_uk_postcode(map.get(cells, "pc")?).filter(|(_, match_type)| *match_type == PcMatchType::Full)?;
+  let terminated = termination_date(cells, map);
+  // the ONS directory gives postcodes without a grid reference a latitude of 99.999999
+  let lat_lng = map.get(cells, "lat").and_then(|v| v.parse::<f64>().ok()).filter(|v| (-90.0..=90.0).contains(v))
+    .zip(map.get(cells, "lng").and_then(|v| v.parse::<f64>().ok()).filter(|v| (-180.0..=180.0).contains(v)))
+    .filter(|(lat, lng)| *lat != 0.0 || *lng != 0.0);
+  if lat_lng.is_none() && terminated.is_none() {
+    return None;
+  }
+  let mut values = doc! {
+    "pc": pc.clone(),
+    "modifiedAt": DateTime::now()
+  };
+  if let Some((lat, lng)) = lat_lng {
+    values.insert("lat", lat);
+    values.insert("lng", lng);
+    values.insert(get_zones_geo_field(), doc! { "type": "Point", "coordinates": [lng, lat] });
+  }
+  if let Some(date) = terminated {
+    values.insert("terminated", date);
+    let successors: Vec<String> = map.get(cells, "successors").map(|v| {
+      v.split([';', '|']).filter_map(parse_uk_postcode)
+        .filter(|(code, match_type)| *match_type == PcMatchType::Full && *code != pc)
+        .map(|(code, _)| code).collect()
+    }).unwrap_or_default();
+    if !successors.is_empty() {
+      values.insert("successors", successors);
+    }
+  }
+  let easting = map.get(cells, "e").and_then(|v| v.parse::<f64>().ok());
+  let northing = map.get(cells, "n").and_then(|v| v.parse::<f64>().ok());
+  if let (Some(e), Some(n)) = (easting, northing) {
+    values.insert("e", e);
+    values.insert("n", n);
+  }
+  let gr = map.get(cells, "gr").map(|v| v.to_string())
+    .or_else(|| if pc.starts_with("BT") { None } else { os_grid_ref(easting?, northing?) });
+  if let Some(gr) = gr {
+    values.insert("gr", gr);
+  }
+  for field in ["w", "wc", "d", "cy", "lc"] {
+    // name fields holding ONS codes, e.g. E05000026, are left unchanged
+    if let Some(value) = map.get(cells, field).filter(|v| field == "wc" || !is_ons_code(v)) {
+      values.insert(field, value);
+    }
+  }
+  if let Some(country) = map.get(cells, "c") {
+    let name = COUNTRY_CODES.iter().find(|(code, _)| *code == country).map(|(_, name)| *name).unwrap_or(country);
+    if !is_ons_code(name) {
+      values.insert("c", name);
+    }
+  }
+  Some((pc, values))
+}
+
+/// Termination date from a column such as doterm (200112, stored as 2001-12),
+/// or "unknown" if In Use? is No without a date
+fn termination_date(cells: &[String], map: &ColumnMap) -> Option<String> {
+  let date = map.get(cells, "terminated")
+    .filter(|v| !["no", "false", "0"].contains(&v.to_lowercase().as_str()))
+    .map(|v| if v.len() == 6 && v.chars().all(|c| c.is_ascii_digit()) { format!("{}-{}", &v[..4], &v[4..]) } else { v.to_string() });
+  let not_in_use = map.get(cells, "in_use").map(|v| v.eq_ignore_ascii_case("no")).unwrap_or(false);
+  date.or(if not_in_use { Some("unknown".to_string()) } else { None })
+}
+
+fn is_ons_code(value: &str) -> bool {
+  value.len() == 9 && value.chars().next().map(|c| c.is_ascii_uppercase()).unwrap_or(false) && value[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+#[derive(Debug, Copy, Clone)]
+enum UpsertOutcome {
+  Inserted,
+  Updated,
+  Failed,
+}
+
+async fn upsert_zone(collection: &Collection<Document>, pc: &str, values: Document) -> UpsertOutcome {
+  let options = UpdateOptions::builder().upsert(true).build();
+  let update = if values.contains_key("terminated") {
+    doc! { "$set": values }
+  } else {
+    // a live row re-issues a postcode that may have been terminated before
+    doc! { "$set": values, "$unset": { "terminated": "", "successors": "" } }
+  };
+  match collection.update_one(doc! { "pc": pc }, update, options).await {
+    Ok(result) if result.upserted_id.is_some() => UpsertOutcome::Inserted,
+    Ok(_) => UpsertOutcome::Updated,
+    Err(error) => {
+      tracing::warn!("failed to upsert {}: {}", pc, error);
+      UpsertOutcome::Failed
+    }
+  }
+}
+
+fn read_progress(path: &str) -> ImportCounts {
+  fs::read_to_string(path).ok()
+    .and_then(|text| serde_json::from_str::<ImportCounts>(&text).ok())
+    .unwrap_or_default()
+}
+
+fn write_progress(path: &str, counts: &ImportCounts) {
+  if let Ok(text) = serde_json::to_string(counts) {
+    if let Err(error) = fs::write(path, text) {
+      tracing::warn!("cannot save import progress to {}: {}", path, error);
+    }
+  }
+}
+
+async fn upsert_batch(collection: &Collection<Document>, batch: Vec<(String, Document)>, counts: &mut ImportCounts) {
+  let outcomes: Vec<UpsertOutcome> = stream::iter(batch)
+    .map(|(pc, values)| async move { upsert_zone(collection, &pc, values).await })
+    .buffer_unordered(UPSERT_CONCURRENCY)
+    .collect()
+    .await;
+  let mut failed = 0;
+  for outcome in outcomes {
+    match outcome {
+      UpsertOutcome::Inserted => counts.inserted += 1,
+      UpsertOutcome::Updated => counts.updated += 1,
+      UpsertOutcome::Failed => failed += 1
+    }
+  }
+  counts.failed += failed;
+}
+
+/// Upserts every postcode in the file into zones, keeping terminated ones for redirects. Progress is saved to `<file>.progress`
+/// after each batch and the import resumes after the last saved line unless restarted.
+/// The import stops after the first batch with a failed upsert, leaving the progress file at
+/// the end of the previous batch so a rerun retries that whole batch.
+pub async fn import_postcodes(client: &Client, options: &ImportOptions) -> Result<ImportCounts, String> {
+  let file = File::open(&options.path).map_err(|e| format!("{}: {}", options.path, e))?;
+  let tsv = Path::new(&options.path).extension().map(|ext| ext.eq_ignore_ascii_case("tsv")).unwrap_or(false);
+  let mut lines = BufReader::new(file).lines();
+  let header = lines.next().and_then(|line| line.ok()).ok_or(format!("{} is empty", options.path))?;
+  let map = ColumnMap::new(&split_line(header.trim_start_matches('\u{feff}'), tsv))?;
+  let progress_path = options.progress_path();
+  let mut counts = if options.restart { ImportCounts::default() } else { read_progress(&progress_path) };
+  if counts.lines > 0 {
+    println!("resuming after line {}", counts.lines);
+  }
+  let collection: Collection<Document> = client.database(&get_db_name()).collection::<Document>("zones");
+  let mut batch: Vec<(String, Document)> = Vec::with_capacity(options.batch_size);
+  let mut line_num = 0;
+  for line in lines {
+    let line = line.map_err(|e| format!("{}: {}", options.path, e))?;
+    line_num += 1;
+    if line_num <= counts.lines {
+      continue;
+    }
+    match build_zone_values(&split_line(&line, tsv), &map) {
+      Some(entry) => {
+        if entry.1.contains_key("terminated") {
+          counts.terminated += 1;
+        }
+        batch.push(entry);
+      },
+      None => counts.skipped += 1
+    }
+    if batch.len() >= options.batch_size {
+      upsert_batch(&collection, std::mem::take(&mut batch), &mut counts).await;
+      println!("{} lines: {} inserted, {} updated, {} skipped, {} failed", line_num, counts.inserted, counts.updated, counts.skipped, counts.failed);
+      if counts.failed > 0 {
+        return Ok(counts);
+      }
+      counts.lines = line_num;
+      write_progress(&progress_path, &counts);
+    }
+  }
+  upsert_batch(&collection, batch, &mut counts).await;
+  if counts.failed > 0 {
+    return Ok(counts);
+  }
+  counts.lines = line_num.max(counts.lines);
+  write_progress(&progress_path, &counts);
+  counts.successors += assign_successors(client, &collection).await?;
+  let _ = fs::remove_file(&progress_path);
+  Ok(counts)
+}
+
+/// Records the nearest live postcodes within SUCCESSOR_KM as successors of each terminated postcode
+/// with coordinates and none listed. Only postcodes still without successors are visited,
+/// so an interrupted pass continues where it stopped.
+async fn assign_successors(client: &Client, collection: &Collection<Document>) -> Result<usize, String> {
+  let filter = doc! { "terminated": { "$exists": true }, "successors": { "$exists": false }, "lat": { "$exists": true } };
+  let cursor = collection.find(filter, None).await.map_err(|e| e.to_string())?;
+  let assigned: Vec<bool> = cursor
+    .filter_map(|row| async move { row.ok() })
+    .map(|row| async move {
+      let pc = row.get_str("pc").unwrap_or_default().to_owned();
+      let geo = Geo::simple(row.get_f64("lat").unwrap_or(0.0), row.get_f64("lng").unwrap_or(0.0));
+      let successors = fetch_live_pcs_near(client, geo, SUCCESSOR_KM, MAX_SUCCESSORS).await.unwrap_or_default();
+      if successors.is_empty() {
+        return false;
+      }
+      collection.update_one(doc! { "pc": &pc }, doc! { "$set": { "successors": successors } }, None).await.is_ok()
+    })
+    .buffer_unordered(UPSERT_CONCURRENCY)
+    .collect()
+    .await;
+  Ok(assigned.into_iter().filter(|done| *done).count())
+}
+
+/// Runs the import-postcodes subcommand and returns the process exit code
+pub async fn run_import_command(client: &Client, args: &[String]) -> i32 {
+  let options = match ImportOptions::from_args(args) {
+    Ok(options) => options,
+    Err(message) => {
+      eprintln!("{}", message);
+      return 2;
+    }
+  };
+  match import_postcodes(client, &options).await {
+    Ok(counts) if counts.failed > 0 => {
+      eprintln!("imported {} with {} failed upserts: {} inserted, {} updated, {} skipped. Rerun to retry from line {}", options.path, counts.failed, counts.inserted, counts.updated, counts.skipped, counts.lines + 1);
+      1
+    },
+    Ok(counts) => {
+      println!("imported {}: {} inserted, {} updated, {} skipped, {} terminated, {} successors assigned", options.path, counts.inserted, counts.updated, counts.skipped, counts.terminated, counts.successors);
+      0
+    },
+    Err(message) => {
+      eprintln!("import failed: {}", message);
+      1
+    }
+  }
+}
@@ -0,0 +1,316 @@
+use crate::solar::SolarPosition;
+
+const DAY_SECS: f64 = 24.0 * 60.0 * 60.0;
+
+/// Mean daily increase of the moon's elongation from the sun in degrees
+const MEAN_ELONGATION_RATE: f64 = 12.190749;
+
+/// Periodic terms of the moon's longitude as multiples of D, M, M' and F with
+/// coefficients in millionths of a degree, the largest terms of Meeus table 47.A
+const LONGITUDE_TERMS: [(f64, f64, f64, f64, f64); 34] = [
+  (0.0, 0.0, 1.0, 0.0, 6288774.0),
+  (2.0, 0.0, -1.0, 0.0, 1274027.0),
+  (2.0, 0.0, 0.0, 0.0, 658314.0),
+  (0.0, 0.0, 2.0, 0.0, 213618.0),
+  (0.0, 1.0, 0.0, 0.0, -185116.0),
+  (0.0, 0.0, 0.0, 2.0, -114332.0),
+  (2.0, 0.0, -2.0, 0.0, 58793.0),
+  (2.0, -1.0, -1.0, 0.0, 57066.0),
+  (2.0, 0.0, 1.0, 0.0, 53322.0),
+  (2.0, -1.0, 0.0, 0.0, 45758.0),
+  (0.0, 1.0, -1.0, 0.0, -40923.0),
+  (1.0, 0.0, 0.0, 0.0, -34720.0),
+  (0.0, 1.0, 1.0, 0.0, -30383.0),
+  (2.0, 0.0, 0.0, -2.0, 15327.0),
+  (0.0, 0.0, 1.0, 2.0, -12528.0),
+  (0.0, 0.0, 1.0, -2.0, 10980.0),
+  (4.0, 0.0, -1.0, 0.0, 10675.0),
+  (0.0, 0.0, 3.0, 0.0, 10034.0),
+  (4.0, 0.0, -2.0, 0.0, 8548.0),
+  (2.0, 1.0, -1.0, 0.0, -7888.0),
+  (2.0, 1.0, 0.0, 0.0, -6766.0),
+  (1.0, 0.0, -1.0, 0.0, -5163.0),
+  (1.0, 1.0, 0.0, 0.0, 4987.0),
+  (2.0, -1.0, 1.0, 0.0, 4036.0),
+  (2.0, 0.0, 2.0, 0.0, 3994.0),
+  (4.0, 0.0, 0.0, 0.0, 3861.0),
+  (2.0, 0.0, -3.0, 0.0, 3665.0),
+  (0.0, 1.0, -2.0, 0.0, -2689.0),
+  (2.0, 0.0, -1.0, 2.0, -2602.0),
+  (2.0, -1.0, -2.0, 0.0, 2390.0),
+  (1.0, 0.0, 1.0, 0.0, -2348.0),
+  (2.0, -2.0, 0.0, 0.0, 2236.0),
+  (0.0, 1.0, 2.0, 0.0, -2120.0),
+  (0.0, 2.0, 0.0, 0.0, -2069.0),
+];
+
+/// Periodic terms of the moon's latitude, the largest terms of Meeus table 47.B
+const LATITUDE_TERMS: [(f64, f64, f64, f64, f64); 30] = [
+  (0.0, 0.0, 0.0, 1.0, 5128122.0),
+  (0.0, 0.0, 1.0, 1.0, 280602.0),
+  (0.0, 0.0, 1.0, -1.0, 277693.0),
+  (2.0, 0.0, 0.0, -1.0, 173237.0),
+  (2.0, 0.0, -1.0, 1.0, 55413.0),
+  (2.0, 0.0, -1.0, -1.0, 46271.0),
+  (2.0, 0.0, 0.0, 1.0, 32573.0),
+  (0.0, 0.0, 2.0, 1.0, 17198.0),
+  (2.0, 0.0, 1.0, -1.0, 9266.0),
+  (0.0, 0.0, 2.0, -1.0, 8822.0),
+  (2.0, -1.0, 0.0, -1.0, 8216.0),
+  (2.0, 0.0, -2.0, -1.0, 4324.0),
+  (2.0, 0.0, 1.0, 1.0, 4200.0),
+  (2.0, 1.0, 0.0, -1.0, -3359.0),
+  (2.0, -1.0, -1.0, 1.0, 2463.0),
+  (2.0, -1.0, 0.0, 1.0, 2211.0),
+  (2.0, -1.0, -1.0, -1.0, 2065.0),
+  (0.0, 1.0, -1.0, -1.0, -1870.0),
+  (4.0, 0.0, -1.0, -1.0, 1828.0),
+  (0.0, 1.0, 0.0, 1.0, -1794.0),
+  (0.0, 0.0, 0.0, 3.0, -1749.0),
+  (0.0, 1.0, -1.0, 1.0, -1565.0),
+  (1.0, 0.0, 0.0, 1.0, -1491.0),
+  (0.0, 1.0, 1.0, 1.0, -1475.0),
+  (0.0, 1.0, 1.0, -1.0, -1410.0),
+  (0.0, 1.0, 0.0, -1.0, -1344.0),
+  (1.0, 0.0, 0.0, -1.0, -1335.0),
+  (0.0, 0.0, 3.0, 1.0, 1107.0),
+  (4.0, 0.0, 0.0, -1.0, 1021.0),
+  (4.0, 0.0, -1.0, 1.0, 833.0),
+];
+
+/// Geocentric altitude of the moon's centre at moonrise and moonset, allowing for
+/// refraction, the lunar disc and the moon's mean horizontal parallax
+pub const MOONRISE_ALTITUDE: f64 = 0.125;
+
+/// Fundamental arguments of the lunar theory in degrees for Julian centuries since J2000
+struct LunarArgs {
+  t: f64,
+  mean_long: f64,
+  elong: f64,
+  sun_anom: f64,
+  moon_anom: f64,
+  arg_lat: f64,
+  ecc: f64,
+}
+
+impl LunarArgs {
+  fn at(ts: i64) -> Self {
+    let t = (julian_day_converter::unixtime_to_julian_day(ts) - 2451545.0) / 36525.0;
+    LunarArgs {
+      t,
+      mean_long: 218.3164477 + t * (481267.88123421 + t * (-0.0015786 + t * (1.0 / 538841.0 - t / 65194000.0))),
+      elong: 297.8501921 + t * (445267.1114034 + t * (-0.0018819 + t * (1.0 / 545868.0 - t / 113065000.0))),
+      sun_anom: 357.5291092 + t * (35999.0502909 + t * (-0.0001536 + t / 24490000.0)),
+      moon_anom: 134.9633964 + t * (477198.8675055 + t * (0.0087414 + t * (1.0 / 69699.0 - t / 14712000.0))),
+      arg_lat: 93.2720950 + t * (483202.0175233 + t * (-0.0036539 + t * (-1.0 / 3526000.0 + t / 863310000.0))),
+      // the eccentricity of the earth's orbit weakens terms involving the sun's anomaly
+      ecc: 1.0 - t * (0.002516 + t * 0.0000074)
+    }
+  }
+
+  fn sum_terms(&self, terms: &[(f64, f64, f64, f64, f64)]) -> f64 {
+    terms.iter().map(|&(d, m, mp, f, coeff)| {
+      let arg = (d * self.elong + m * self.sun_anom + mp * self.moon_anom + f * self.arg_lat).to_radians();
+      coeff * self.ecc.powi(m.abs() as i32) * arg.sin()
+    }).sum()
+  }
+
+  fn venus(&self) -> f64 {
+    (119.75 + 131.849 * self.t).to_radians()
+  }
+
+  fn nutation(&self) -> f64 {
+    -0.00478 * (125.04 - 1934.136 * self.t).to_radians().sin()
+  }
+
+  fn longitude(&self) -> f64 {
+    let jupiter = (53.09 + 479264.290 * self.t).to_radians();
+    let sum = self.sum_terms(&LONGITUDE_TERMS)
+      + 3958.0 * self.venus().sin()
+      + 1962.0 * (self.mean_long - self.arg_lat).to_radians().sin()
+      + 318.0 * jupiter.sin();
+    (self.mean_long + sum / 1_000_000.0 + self.nutation()).rem_euclid(360.0)
+  }
+
+  fn latitude(&self) -> f64 {
+    let a3 = (313.45 + 481266.484 * self.t).to_radians();
+    let (l_rad, f_rad, mp_rad) = (self.mean_long.to_radians(), self.arg_lat.to_radians(), self.moon_anom.to_radians());
+    let sum = self.sum_terms(&LATITUDE_TERMS)
+      - 2235.0 * l_rad.sin()
+      + 382.0 * a3.sin()
+      + 175.0 * (self.venus() - f_rad).sin()
+      + 175.0 * (self.venus() + f_rad).sin()
+      + 127.0 * (l_rad - mp_rad).sin()
+      - 115.0 * (l_rad + mp_rad).sin();
+    sum / 1_000_000.0
+  }
+
+  /// True obliquity of the ecliptic
+  fn obliquity(&self) -> f64 {
+    let t = self.t;
+    23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60.0) / 60.0
+      + 0.00256 * (125.04 - 1934.136 * t).to_radians().cos()
+  }
+}
+
+/// Apparent ecliptic longitude of the moon in degrees, accurate to about 0.01°
+pub fn moon_longitude(ts: i64) -> f64 {
+  LunarArgs::at(ts).longitude()
+}
+
+/// Geocentric altitude of the moon in degrees as seen from a latitude and longitude
+pub fn moon_altitude(lat: f64, lng: f64, ts: i64) -> f64 {
+  let args = LunarArgs::at(ts);
+  let (lambda, beta, eps) = (args.longitude().to_radians(), args.latitude().to_radians(), args.obliquity().to_radians());
+  let ra = (lambda.sin() * eps.cos() - beta.tan() * eps.sin()).atan2(lambda.cos());
+  let decl = (beta.sin() * eps.cos() + beta.cos() * eps.sin() * lambda.sin()).asin();
+  let days = julian_day_converter::unixtime_to_julian_day(ts) - 2451545.0;
+  let sidereal = 280.46061837 + 360.98564736629 * days + 0.000387933 * args.t * args.t;
+  let hour_angle = (sidereal + lng).to_radians() - ra;
+  let lat_rad = lat.to_radians();
+  (lat_rad.sin() * decl.sin() + lat_rad.cos() * decl.cos() * hour_angle.cos()).asin().to_degrees()
+}
+
+/// First moonrise and moonset between two timestamps, found by sampling the moon's altitude
+/// hourly and bisecting each horizon crossing to within a minute
+pub fn moon_rise_set(lat: f64, lng: f64, start_ts: i64, end_ts: i64) -> (Option<i64>, Option<i64>) {
+  let above = |ts: i64| moon_altitude(lat, lng, ts) - MOONRISE_ALTITUDE;
+  let (mut rise, mut set) = (None, None);
+  let mut prev_ts = start_ts;
+  let mut prev_alt = above(prev_ts);
+  while prev_ts < end_ts && (rise.is_none() || set.is_none()) {
+    let next_ts = (prev_ts + 3600).min(end_ts);
+    let next_alt = above(next_ts);
+    if (prev_alt < 0.0) != (next_alt < 0.0) {
+      let rising = prev_alt < 0.0;
+      let (mut low, mut high) = (prev_ts, next_ts);
+      while high - low > 60 {
+        let mid = low + (high - low) / 2;
+        if (above(mid) < 0.0) == rising {
+          low = mid;
+        } else {
+          high = mid;
+        }
+      }
+      if rising && rise.is_none() {
+        rise = Some(high);
+      } else if !rising && set.is_none() {
+        set = Some(high);
+      }
+    }
+    prev_ts = next_ts;
+    prev_alt = next_alt;
+  }
+  (rise, set)
+}
+
+/// Angle of the moon east of the sun along the ecliptic, 0° at new moon and 180° at full moon
+pub fn sun_moon_angle(ts: i64) -> f64 {
+  (moon_longitude(ts) - SolarPosition::at(ts).longitude).rem_euclid(360.0)
+}
+
+/// Illuminated fraction of the moon's disc for a sun-moon angle
+pub fn illumination(sun_angle: f64) -> f64 {
+  (1.0 - sun_angle.to_radians().cos()) / 2.0
+}
+
+/// Principal phase numbered 1 for new moon, 2 for first quarter, 3 for full moon and 4 for last quarter
+pub fn phase_angle(num: u8) -> f64 {
+  (num.saturating_sub(1) % 4) as f64 * 90.0
+}
+
+/// Number of the principal phase most recently passed
+pub fn current_phase(sun_angle: f64) -> u8 {
+  (sun_angle.rem_euclid(360.0) / 90.0).floor() as u8 % 4 + 1
+}
+
+/// First time after `ts` when the sun-moon angle reaches the phase angle, refined to within a minute
+pub fn next_phase_ts(ts: i64, num: u8) -> i64 {
+  let target = phase_angle(num);
+  let ahead = (target - sun_moon_angle(ts)).rem_euclid(360.0);
+  let mut phase_ts = ts + (ahead / MEAN_ELONGATION_RATE * DAY_SECS).round() as i64;
+  for _ in 0..5 {
+    let diff = (target - sun_moon_angle(phase_ts) + 540.0).rem_euclid(360.0) - 180.0;
+    let step = (diff / MEAN_ELONGATION_RATE * DAY_SECS).round() as i64;
+    phase_ts += step;
+    if step.abs() < 60 {
+      break;
+    }
+  }
+  phase_ts
+}
+
+/// The next four principal phases after `ts` in chronological order
+pub fn next_phases(ts: i64) -> Vec<(u8, i64)> {
+  let mut phases: Vec<(u8, i64)> = (1..=4).map(|num| (num, next_phase_ts(ts, num))).collect();
+  phases.sort_by_key(|(_, phase_ts)| *phase_ts);
+  phases
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::{TimeZone, Utc};
+  use super::*;
+
+  /// Phase times recorded from the remote astro API agree to within this many minutes
+  const PHASE_TOLERANCE_MINS: i64 = 5;
+
+  fn utc_ts(y: i32, m: u32, d: u32, h: u32, min: u32) -> i64 {
+    Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap().timestamp()
+  }
+
+  fn assert_near(actual: i64, expected: i64, tolerance_mins: i64) {
+    let diff_mins = (actual - expected).abs() / 60;
+    assert!(diff_mins <= tolerance_mins, "{} is {} minutes from {}", actual, diff_mins, expected);
+  }
+
+  #[test]
+  fn next_phases_match_remote_times() {
+    let phases = next_phases(utc_ts(2024, 1, 20, 0, 0));
+    let expected = [
+      (3, utc_ts(2024, 1, 25, 17, 54)),
+      (4, utc_ts(2024, 2, 2, 23, 18)),
+      (1, utc_ts(2024, 2, 9, 22, 59)),
+      (2, utc_ts(2024, 2, 16, 15, 1)),
+    ];
+    assert_eq!(phases.len(), expected.len());
+    for ((num, phase_ts), (expected_num, expected_ts)) in phases.into_iter().zip(expected) {
+      assert_eq!(num, expected_num);
+      assert_near(phase_ts, expected_ts, PHASE_TOLERANCE_MINS);
+    }
+  }
+
+  #[test]
+  fn illumination_and_phase_at_full_and_new_moon() {
+    let full = sun_moon_angle(utc_ts(2024, 1, 25, 17, 54));
+    assert!(illumination(full) > 0.999);
+    assert!((179.0..181.0).contains(&full));
+    let new = sun_moon_angle(utc_ts(2024, 2, 9, 22, 59));
+    assert!(illumination(new) < 0.001);
+    // a day after each principal phase
+    assert_eq!(current_phase(sun_moon_angle(utc_ts(2024, 1, 26, 18, 0))), 3);
+    assert_eq!(current_phase(sun_moon_angle(utc_ts(2024, 2, 10, 23, 0))), 1);
+    assert_eq!(current_phase(sun_moon_angle(utc_ts(2024, 2, 3, 23, 0))), 4);
+  }
+
+  #[test]
+  fn position_matches_meeus_example_47a() {
+    // 1992 April 12 at 0h TD, apparent longitude 133.167265° and latitude -3.229126°
+    let args = LunarArgs::at(utc_ts(1992, 4, 12, 0, 0));
+    assert!((args.longitude() - 133.167265).abs() < 0.005, "longitude {}", args.longitude());
+    assert!((args.latitude() + 3.229126).abs() < 0.005, "latitude {}", args.latitude());
+  }
+
+  #[test]
+  fn moonrise_and_moonset_in_london() {
+    let (lat, lng) = (51.5074, -0.1278);
+    let (rise, set) = moon_rise_set(lat, lng, utc_ts(2024, 1, 25, 0, 0), utc_ts(2024, 1, 26, 0, 0));
+    // times recorded from this calculation, with the position checked against Meeus above
+    // and the horizon crossing at the standard lunar altitude
+    let rise = rise.expect("moonrise on 25 January");
+    assert_near(rise, utc_ts(2024, 1, 25, 16, 0), 3);
+    assert_near(set.expect("moonset on 25 January"), utc_ts(2024, 1, 25, 8, 21), 3);
+    // bisection stops within a minute of the crossing
+    assert!((moon_altitude(lat, lng, rise) - MOONRISE_ALTITUDE).abs() < 0.25);
+  }
+}
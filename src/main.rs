@@ -16,9 +16,18 @@ mod geonames;
 mod simple_iso;
 mod handlers;
 mod astro;
+mod state;
+mod errors;
+mod upstream;
+mod timezones;
+mod solar;
+mod lunar;
+mod gazetteer;
+mod importer;
 
 //use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use axum::{
@@ -42,25 +51,47 @@ use tower_http::{
 use crate::common::{welcome, handler_404};
 use crate::handlers::{
     get_nearest_pcs,
+    get_pcs_in_bounds,
+    get_pcs_in_polygon,
+    get_pc_lookup,
+    get_pc_autocomplete,
     get_gtz,
+    get_gtz_batch,
     fetch_and_update_addresses,
     get_weather_report,
     get_places_of_interest,
     get_nearby_wiki_summaries,
     get_geo_data,
     show_astro_data,
+    show_astro_calendar,
     show_place_lookup,
     show_timezone,
-    get_geo_data_by_pc
+    show_tz_transitions,
+    convert_time,
+    get_geo_data_by_pc,
+    show_health
 };
 
 use crate::db::*;
-// use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use crate::gazetteer::Gazetteer;
+use crate::importer::run_import_command;
+use crate::state::AppState;
+use crate::store::build_store;
+use crate::timezones::TzBoundaryIndex;
+use crate::upstream::UpstreamClient;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 #[tokio::main]
 async fn main() {
-    // initialize tracing
     dotenv().ok();
+    // initialize tracing after .env so RUST_LOG can be set there
+    tracing_subscriber::registry()
+        .with(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new("geofinder=info,tower_http=warn")),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
 
     let database_config = DatabaseConfig::new();
     let mut client_options = ClientOptions::parse(database_config.uri).await.unwrap();
@@ -70,34 +101,54 @@ async fn main() {
     // the server will select the algorithm it supports from the list provided by the driver
     client_options.compressors = database_config.compressors;
     let client = Client::with_options(client_options).unwrap();
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|cmd| cmd == "import-postcodes").unwrap_or(false) {
+        std::process::exit(run_import_command(&client, &args[2..]).await);
+    }
+    let cache_config = CacheConfig::new();
+    let state = AppState::new(client, build_store(&cache_config).await, Arc::new(UpstreamClient::new()), Arc::new(TzBoundaryIndex::from_env()), Arc::new(Gazetteer::from_env()));
+
+    // batches of coordinates and polygons need a larger body limit and more time than single lookups
+    let bulk_routes = Router::new()
+        .route("/gtz-batch", post(get_gtz_batch))
+        .route("/postcodes-in-polygon", post(get_pcs_in_polygon))
+        .layer(TimeoutLayer::new(Duration::from_secs(120)))
+        .layer(RequestBodyLimitLayer::new(128 * 1024));
 
     // build our application with a route
     let app = Router::new()
         // `GET /` goes to `root`
         .route("/", get(welcome))
         .route("/postcodes", get(get_nearest_pcs))
+        .route("/postcodes-in-bounds", get(get_pcs_in_bounds))
         .route("/gtz", get(get_gtz))
         .route("/timezone", get(show_timezone))
+        .route("/tz-transitions", get(show_tz_transitions))
+        .route("/convert-time", post(convert_time))
         .route("/addresses", post(fetch_and_update_addresses))
         .route("/weather", get(get_weather_report))
         .route("/places-of-interest", get(get_places_of_interest))
         .route("/wiki-summaries", get(get_nearby_wiki_summaries))
         .route("/geo-codes", post(get_geo_data))
         .route("/astro", get(show_astro_data))
+        .route("/astro-calendar", get(show_astro_calendar))
         .route("/lookup", get(show_place_lookup))
         .route("/pc-match", post(get_geo_data_by_pc))
-        // .route("/pc-updates", get(read_pc_zone_updates))
+        .route("/pc-lookup", get(get_pc_lookup))
+        .route("/pc-autocomplete", get(get_pc_autocomplete))
+        .route("/health", get(show_health))
         // .layer(CorsLayer::permissive()) // handle in nginx
         // timeout requests after 10 secs, returning 408 status code
         .layer(TimeoutLayer::new(Duration::from_secs(15)))
         // don't allow request bodies larger than 1024 bytes, returning 413 status code
         .layer(RequestBodyLimitLayer::new(2048))
+        .merge(bulk_routes)
         .layer(TraceLayer::new_for_http())
         .layer(SetResponseHeaderLayer::if_not_present(
             header::SERVER,
             HeaderValue::from_static("rust-axum"),
         ));
-    let app = app.fallback(handler_404).with_state(client);
+    let app = app.fallback(handler_404).with_state(state);
     let env_port = if let Ok(port_ref) = dotenv::var("PORT") { port_ref } else { "3000".to_owned() };
     let port = if let Ok(p) = u16::from_str_radix(&env_port, 10) { p } else { 3000 };
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
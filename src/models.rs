@@ -12,7 +12,49 @@ use crate::common::now_datetime_string;
 use crate::extractors::*;
 use crate::bson_extractors::*;
 use crate::simple_iso::*;
+use crate::gazetteer::PlaceMatch;
+use crate::lunar::{current_phase, illumination, moon_longitude, moon_rise_set, next_phases, sun_moon_angle};
+use crate::solar::{equation_of_time, solar_noon, SunCrossing, SunEvents, SUNRISE_ALTITUDE};
+use crate::timezones::{build_next_period, zone_offset_at, ZoneOffset};
+
+
+/// Models with a point location that can be output as RFC 7946 GeoJSON features
+pub trait GeoFeature: Serialize {
+  fn lng_lat(&self) -> (f64, f64);
+
+  /// Point feature with all other serialised fields as properties
+  fn to_feature(&self) -> Value {
+    let (lng, lat) = self.lng_lat();
+    let mut properties = match serde_json::to_value(self) {
+      Ok(Value::Object(map)) => map,
+      _ => Map::new()
+    };
+    properties.remove("lat");
+    properties.remove("lng");
+    json!({
+      "type": "Feature",
+      "geometry": { "type": "Point", "coordinates": [lng, lat] },
+      "properties": properties
+    })
+  }
+}
 
+pub fn build_feature_collection<T: GeoFeature>(items: &[T], properties: Value) -> Value {
+  let features: Vec<Value> = items.iter().map(|item| item.to_feature()).collect();
+  json!({
+    "type": "FeatureCollection",
+    "features": features,
+    "properties": properties
+  })
+}
+
+fn to_section_feature<T: GeoFeature>(item: &T, section: &str) -> Value {
+  let mut feature = item.to_feature();
+  if let Some(properties) = feature.get_mut("properties").and_then(|p| p.as_object_mut()) {
+    properties.insert("section".to_string(), json!(section));
+  }
+  feature
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GeoNearby {
@@ -139,6 +181,12 @@ impl PcRow {
 
 }
 
+impl GeoFeature for PcRow {
+  fn lng_lat(&self) -> (f64, f64) {
+    (self.lng, self.lat)
+  }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PcInfo {
   pub v: String,
@@ -200,6 +248,46 @@ impl ToString for Geo {
   }
 }
 
+#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+pub struct BoundingBox {
+  #[serde(rename="minLat")]
+  pub min_lat: f64,
+  #[serde(rename="minLng")]
+  pub min_lng: f64,
+  #[serde(rename="maxLat")]
+  pub max_lat: f64,
+  #[serde(rename="maxLng")]
+  pub max_lng: f64,
+}
+
+impl BoundingBox {
+  pub fn new(min_lat: f64, min_lng: f64, max_lat: f64, max_lng: f64) -> Self {
+    BoundingBox {
+      min_lat,
+      min_lng,
+      max_lat,
+      max_lng
+    }
+  }
+
+  pub fn is_valid(&self) -> bool {
+    self.min_lat < self.max_lat && self.min_lng < self.max_lng
+      && self.min_lat >= -90.0 && self.max_lat <= 90.0
+      && self.min_lng >= -180.0 && self.max_lng <= 180.0
+  }
+
+  /// Closed outer ring in GeoJSON [lng, lat] order
+  pub fn to_ring(self) -> Vec<Vec<f64>> {
+    vec![
+      vec![self.min_lng, self.min_lat],
+      vec![self.max_lng, self.min_lat],
+      vec![self.max_lng, self.max_lat],
+      vec![self.min_lng, self.max_lat],
+      vec![self.min_lng, self.min_lat],
+    ]
+  }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TzRow {
   abbreviation: String,
@@ -216,6 +304,8 @@ pub struct TzRow {
   ref_unix: i64,
   #[serde(rename="solarUtcOffset")]
   solar_utc_offset: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  solar: Option<SolarTime>,
   utc: String,
   #[serde(rename="weekDay")]
   week_day: u8,
@@ -261,14 +351,52 @@ impl TzRow {
       period,
       ref_unix,
       solar_utc_offset,
+      solar: None,
       week_day,
       zone_name,
       valid
     }
   }
 
+  /// Builds a row from an offset computed locally from the tz database.
+  /// The country code is not known locally and is left empty.
+  pub fn new_local(zone_name: &str, ts: i64, current: &ZoneOffset, period: Option<TzPeriod>) -> TzRow {
+    let mut row = TzRow {
+      abbreviation: current.abbreviation.clone(),
+      country_code: "".to_string(),
+      gmt_offset: current.offset,
+      local_dt: "".to_string(),
+      dst: current.dst,
+      utc: "".to_string(),
+      period,
+      ref_unix: ts,
+      solar_utc_offset: 0,
+      solar: None,
+      week_day: 0,
+      zone_name: zone_name.to_string(),
+      valid: zone_name.contains("/")
+    };
+    row.set_ref_time(ts);
+    row
+  }
+
+  pub fn zone_name(&self) -> &str {
+    &self.zone_name
+  }
+
+  pub fn set_country_code(&mut self, cc: &str) {
+    self.country_code = cc.to_string();
+  }
+
+  /// Sets the mean solar offset from the longitude and the apparent solar time at the reference time
   pub fn calc_solar_offset(&mut self, lng: f64) {
     self.solar_utc_offset = natural_tz_offset_from_utc(lng);
+    self.set_solar_time();
+  }
+
+  fn set_solar_time(&mut self) {
+    let ts = if self.ref_unix != 0 { self.ref_unix } else { Utc::now().timestamp() };
+    self.solar = Some(SolarTime::new(ts, self.solar_utc_offset, self.gmt_offset));
   }
 
   pub fn get_next_period_ts(&self) -> i64 {
@@ -287,6 +415,9 @@ impl TzRow {
     }
   }
 
+  /// Replays the row at another timestamp. Zones known to the tz database get the exact offset,
+  /// abbreviation, DST flag and next period for that moment; otherwise only the one known
+  /// upcoming period can be applied.
   pub fn update_time(&mut self, ts_opt: Option<i64>) {
     let ref_dt = if let Some(ts_val) = ts_opt {
       DateTime::from_timestamp(ts_val,0).unwrap_or(Utc::now())
@@ -294,11 +425,26 @@ impl TzRow {
       Utc::now()
     };
     let ts = ref_dt.timestamp();
-    self.ref_unix = ts_opt.unwrap_or(ref_dt.timestamp());
-    self.utc = ref_dt.to_simple_iso();
-    if ts >= self.get_next_period_ts() {
+    if let Some(current) = zone_offset_at(&self.zone_name, ts) {
+      self.gmt_offset = current.offset;
+      self.dst = current.dst;
+      self.abbreviation = current.abbreviation;
+      self.period = build_next_period(&self.zone_name, ts);
+    } else if self.period.is_some() && ts >= self.get_next_period_ts() {
       self.gmt_offset = self.get_next_period_offset();
     }
+    self.set_ref_time(ts);
+    if self.solar.is_some() {
+      self.set_solar_time();
+    }
+  }
+
+  /// Sets the reference, UTC and local times from the current gmt offset
+  fn set_ref_time(&mut self, ts: i64) {
+    self.ref_unix = ts;
+    if let Some(ref_dt) = DateTime::from_timestamp(ts, 0) {
+      self.utc = ref_dt.to_simple_iso();
+    }
     let offset_ts = ts + self.gmt_offset;
     if let Some(lt) = DateTime::from_timestamp(offset_ts,0) {
       self.local_dt = lt.to_simple_iso();
@@ -308,6 +454,43 @@ impl TzRow {
 
 }
 
+/// Apparent solar time at a location. Offsets and the equation of time are in seconds,
+/// civilDiff being how far civil time runs ahead of apparent solar time.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SolarTime {
+  #[serde(rename="equationOfTime")]
+  pub equation_of_time: i64,
+  #[serde(rename="apparentUtcOffset")]
+  pub apparent_utc_offset: i64,
+  #[serde(rename="civilDiff")]
+  pub civil_diff: i64,
+  #[serde(rename="solarDt")]
+  pub solar_dt: String,
+  pub noon: i64,
+  #[serde(rename="noonUtc")]
+  pub noon_utc: String,
+  #[serde(rename="noonLocalDt")]
+  pub noon_local_dt: String,
+}
+
+impl SolarTime {
+  pub fn new(ts: i64, mean_offset: i64, gmt_offset: i64) -> Self {
+    let equation_of_time = equation_of_time(ts);
+    let apparent_utc_offset = mean_offset + equation_of_time;
+    let noon = solar_noon(ts, mean_offset);
+    let to_iso = |ts: i64| DateTime::from_timestamp(ts, 0).map(|dt| dt.to_simple_iso()).unwrap_or_default();
+    SolarTime {
+      equation_of_time,
+      apparent_utc_offset,
+      civil_diff: gmt_offset - apparent_utc_offset,
+      solar_dt: to_iso(ts + apparent_utc_offset),
+      noon,
+      noon_utc: to_iso(noon),
+      noon_local_dt: to_iso(noon + gmt_offset)
+    }
+  }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 pub struct TzPeriod {
   pub start: Option<i64>,
@@ -317,6 +500,39 @@ pub struct TzPeriod {
   pub next_gmt_offset: Option<i64>,
 }
 
+/// A change of offset or abbreviation. The flattened period starts at the transition,
+/// ends at the following one and carries the new offset as nextGmtOffset.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TzTransition {
+  #[serde(flatten)]
+  pub period: TzPeriod,
+  pub utc: String,
+  #[serde(rename="prevGmtOffset")]
+  pub prev_gmt_offset: i64,
+  #[serde(rename="prevAbbreviation")]
+  pub prev_abbreviation: String,
+  pub abbreviation: String,
+  pub dst: bool,
+}
+
+impl TzTransition {
+  pub fn new(ts: i64, prev: &ZoneOffset, next: &ZoneOffset) -> Self {
+    let utc = DateTime::from_timestamp(ts, 0).map(|dt| dt.to_simple_iso()).unwrap_or_default();
+    TzTransition {
+      period: TzPeriod {
+        start: Some(ts),
+        end: None,
+        next_gmt_offset: Some(next.offset)
+      },
+      utc,
+      prev_gmt_offset: prev.offset,
+      prev_abbreviation: prev.abbreviation.clone(),
+      abbreviation: next.abbreviation.clone(),
+      dst: next.dst
+    }
+  }
+}
+
 impl TzPeriod {
   pub fn new(row: &Map<String, Value>) -> TzPeriod {
     let start = extract_optional_i64_from_value_map(&row, "start");
@@ -337,6 +553,22 @@ pub struct PlaceRow {
   pub text: String,
   #[serde(rename="zoneName")]
   pub zone_name: String,
+  #[serde(default)]
+  pub pop: u32,
+  #[serde(default)]
+  pub fclass: String,
+  #[serde(default)]
+  pub fcode: String,
+  #[serde(rename="adminName", default)]
+  pub admin_name: String,
+  #[serde(default)]
+  pub region: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub cc: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub distance: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub score: Option<f64>,
 }
 
 impl PlaceRow {
@@ -345,13 +577,59 @@ impl PlaceRow {
     let lng = extract_f64_from_value_map(row, "lng");
     let text = extract_string_from_value_map(row, "text");
     let zone_name = extract_string_from_value_map(row, "zoneName");
+    let pop = extract_optional_i64_from_value_map(row, "population").or(extract_optional_i64_from_value_map(row, "pop")).unwrap_or(0) as u32;
+    let fclass = extract_string_from_value_map(row, "fclass");
+    let fcode = extract_string_from_value_map(row, "fcode");
+    let admin_name = extract_string_from_value_map(row, "adminName");
+    let region = extract_string_from_value_map(row, "region");
+    let cc = extract_optional_string_from_value_map(row, "cc").or(extract_optional_string_from_value_map(row, "countryCode"));
     PlaceRow {
       lat,
       lng,
       text,
-      zone_name
+      zone_name,
+      pop,
+      fclass,
+      fcode,
+      admin_name,
+      region,
+      cc,
+      distance: None,
+      score: None
     }
   }
+
+  /// Builds a row from a local gazetteer match
+  pub fn from_gazetteer(matched: &PlaceMatch) -> PlaceRow {
+    let place = matched.place;
+    let mut row = PlaceRow {
+      lat: place.lat,
+      lng: place.lng,
+      text: place.to_text(),
+      zone_name: place.zone_name.as_deref().unwrap_or("").to_string(),
+      pop: place.population,
+      fclass: place.fclass.to_string(),
+      fcode: place.fcode.clone(),
+      admin_name: place.admin_name.to_string(),
+      region: place.region.to_string(),
+      cc: Some(place.cc.clone()),
+      distance: None,
+      score: None
+    };
+    row.set_rank(matched.score, matched.distance);
+    row
+  }
+
+  /// The name before any region or country in the text
+  pub fn name(&self) -> &str {
+    self.text.split(',').next().unwrap_or("").trim()
+  }
+
+  /// Sets the score and distance rounded to 3 decimal places
+  pub fn set_rank(&mut self, score: f64, distance: Option<f64>) {
+    self.score = Some((score * 1000.0).round() / 1000.0);
+    self.distance = distance.map(|km| (km * 1000.0).round() / 1000.0);
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -423,6 +701,22 @@ pub struct PcZone {
   dist: f64,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub pn: Option<String>,
+  /// Year and month a terminated postcode went out of use, e.g. 2001-12
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub terminated: Option<String>,
+  /// Live postcodes replacing a terminated one, nearest first
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub successors: Vec<String>,
+  /// Set when a lookup for a terminated postcode was redirected to this one
+  #[serde(rename="redirectedFrom", default, skip_serializing_if = "Option::is_none")]
+  pub redirected_from: Option<PcRedirect>,
+}
+
+/// A terminated postcode that was requested and the date it went out of use
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PcRedirect {
+  pub pc: String,
+  pub terminated: String,
 }
 
 impl PcZone {
@@ -444,6 +738,8 @@ impl PcZone {
     let w = extract_string(dc, "w");
     let modified_at =  extract_datetime(dc, "modifiedAt");
     let addresses = extract_strings(dc, "addresses");
+    let terminated = Some(extract_string(dc, "terminated")).filter(|v| !v.is_empty());
+    let successors = extract_strings(dc, "successors");
     PcZone {
       pc,
       addresses,
@@ -462,7 +758,10 @@ impl PcZone {
       gr,
       dist,
       modified_at,
-      pn: None
+      pn: None,
+      terminated,
+      successors,
+      redirected_from: None
     }
   }
 
@@ -496,7 +795,10 @@ impl PcZone {
         gr: "".to_string(),
         dist,
         modified_at,
-        pn: Some(place_name)
+        pn: Some(place_name),
+        terminated: None,
+        successors: vec![],
+        redirected_from: None
     }
   }
 
@@ -522,7 +824,10 @@ impl PcZone {
       gr: "".to_string(),
       dist: 0.0,
       modified_at,
-      pn: Some(geo.name.clone())
+      pn: Some(geo.name.clone()),
+      terminated: None,
+      successors: vec![],
+      redirected_from: None
   }
   }
 
@@ -545,6 +850,84 @@ impl PcZone {
     self.pn = Some(place_name.to_string());
   }
 
+  pub fn is_terminated(&self) -> bool {
+    self.terminated.is_some()
+  }
+
+  /// Terminated postcodes may be kept without coordinates
+  pub fn has_geo(&self) -> bool {
+    self.lat != 0.0 || self.lng != 0.0
+  }
+
+  /// Marks this live postcode as the replacement for a terminated one
+  pub fn set_redirect(&mut self, terminated: &PcZone) {
+    self.redirected_from = Some(PcRedirect {
+      pc: terminated.pc.clone(),
+      terminated: terminated.terminated.clone().unwrap_or_default()
+    });
+  }
+
+}
+
+impl GeoFeature for PcZone {
+  fn lng_lat(&self) -> (f64, f64) {
+    (self.lng, self.lat)
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PcMatchType {
+  Full,
+  Sector,
+  Outcode,
+}
+
+/// Postcodes matching a full, sector or outward code with their centroid and extent
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PcLookup {
+  pub code: String,
+  #[serde(rename="type")]
+  pub match_type: PcMatchType,
+  pub count: u32,
+  pub lat: f64,
+  pub lng: f64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub bounds: Option<BoundingBox>,
+  pub zones: Vec<PcZone>,
+}
+
+impl PcLookup {
+  pub fn new_full(zone: PcZone) -> Self {
+    PcLookup {
+      code: zone.pc.clone(),
+      match_type: PcMatchType::Full,
+      count: 1,
+      lat: zone.lat,
+      lng: zone.lng,
+      bounds: None,
+      zones: vec![zone]
+    }
+  }
+
+  /// Builds a partial match from a $group summary with count, centroid and min/max coordinates
+  pub fn new_partial(code: &str, match_type: PcMatchType, summary: &Document, zones: Vec<PcZone>) -> Self {
+    let bounds = BoundingBox::new(
+      extract_f64(summary, "minLat"),
+      extract_f64(summary, "minLng"),
+      extract_f64(summary, "maxLat"),
+      extract_f64(summary, "maxLng")
+    );
+    PcLookup {
+      code: code.to_string(),
+      match_type,
+      count: extract_i32(summary, "count").max(0) as u32,
+      lat: extract_f64(summary, "lat"),
+      lng: extract_f64(summary, "lng"),
+      bounds: Some(bounds),
+      zones
+    }
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -564,6 +947,12 @@ impl SimplePlace {
   }
 }
 
+impl GeoFeature for SimplePlace {
+  fn lng_lat(&self) -> (f64, f64) {
+    (self.lng, self.lat)
+  }
+}
+
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PlaceOfInterest {
@@ -603,6 +992,12 @@ impl PlaceOfInterest {
   }
 }
 
+impl GeoFeature for PlaceOfInterest {
+  fn lng_lat(&self) -> (f64, f64) {
+    (self.lng, self.lat)
+  }
+}
+
 pub fn build_pois(data: Map<String, Value>) -> Vec<PlaceOfInterest> {
   let mut rows:Vec<PlaceOfInterest> = vec![];
   let mut names: HashSet<String> = HashSet::new();
@@ -718,6 +1113,12 @@ impl WeatherReport {
   }
 }
 
+impl GeoFeature for WeatherReport {
+  fn lng_lat(&self) -> (f64, f64) {
+    (self.lng, self.lat)
+  }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WikipediaSummary {
   pub lat: f64,
@@ -757,6 +1158,31 @@ impl WikipediaSummary {
   }
 }
 
+impl GeoFeature for WikipediaSummary {
+  fn lng_lat(&self) -> (f64, f64) {
+    (self.lng, self.lat)
+  }
+}
+
+/// A LocationInfo section left empty because its upstream service failed or is short-circuited,
+/// as opposed to the service answering with no data
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SkippedSection {
+  pub section: String,
+  pub service: String,
+  pub code: String,
+}
+
+impl SkippedSection {
+  pub fn new(section: &str, service: &str, code: &str) -> Self {
+    SkippedSection {
+      section: section.to_string(),
+      service: service.to_string(),
+      code: code.to_string()
+    }
+  }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LocationInfo {
   pub matched: bool,
@@ -780,13 +1206,15 @@ pub struct LocationInfo {
   pub cached: bool,
   pub weather: Option<WeatherReport>,
   pub poi: Vec<PlaceOfInterest>,
-  pub wikipedia: Vec<WikipediaSummary>
+  pub wikipedia: Vec<WikipediaSummary>,
+  #[serde(default)]
+  pub skipped: Vec<SkippedSection>
 }
 
 impl LocationInfo {
-  pub fn new(zones: Vec<PcZone>, places: Vec<SimplePlace>, states: Vec<SimplePlace>, weather: Option<WeatherReport>, poi: Vec<PlaceOfInterest>, wikipedia: Vec<WikipediaSummary>) -> Self {
-    let valid = places.len() > 0;
-    let matched = places.len() > 0;
+  /// `matched` is true when the place or postcode zones resolved, or when neither was requested
+  pub fn new(matched: bool, zones: Vec<PcZone>, places: Vec<SimplePlace>, states: Vec<SimplePlace>, weather: Option<WeatherReport>, poi: Vec<PlaceOfInterest>, wikipedia: Vec<WikipediaSummary>) -> Self {
+    let valid = matched;
     let has_poi = poi.len() > 0;
     let num = zones.len() as u32;
     let zone = zones.get(0).map(|z| z.to_owned());
@@ -819,10 +1247,48 @@ impl LocationInfo {
       weather,
       poi,
       wikipedia,
-      cached: false
+      cached: false,
+      skipped: vec![]
     }
 
   }
+
+  pub fn set_skipped(&mut self, skipped: Vec<SkippedSection>) {
+    self.skipped = skipped;
+  }
+
+  /// All located sections as one FeatureCollection, each feature tagged with its section
+  pub fn to_feature_collection(&self) -> Value {
+    let mut features: Vec<Value> = vec![];
+    if let Some(zone) = self.zone.as_ref() {
+      features.push(to_section_feature(zone, "zone"));
+    }
+    features.extend(self.surrounding.iter().map(|item| to_section_feature(item, "surrounding")));
+    features.extend(self.places.iter().map(|item| to_section_feature(item, "places")));
+    if let Some(weather) = self.weather.as_ref() {
+      features.push(to_section_feature(weather, "weather"));
+    }
+    features.extend(self.poi.iter().map(|item| to_section_feature(item, "poi")));
+    features.extend(self.wikipedia.iter().map(|item| to_section_feature(item, "wikipedia")));
+    json!({
+      "type": "FeatureCollection",
+      "features": features,
+      "properties": {
+        "valid": self.valid,
+        "matched": self.matched,
+        "hasWeather": self.has_weather,
+        "hasPoi": self.has_poi,
+        "hasWikiEntries": self.has_wiki_entries,
+        "hasNearestAddress": self.has_nearest_address,
+        "hasPCs": self.has_pcs,
+        "num": self.num,
+        "states": self.states,
+        "cached": self.cached,
+        "skipped": self.skipped,
+        "distanceUnits": { "zone": "m", "poi": "km", "wikipedia": "km" }
+      }
+    })
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -877,6 +1343,8 @@ pub struct MoonData {
   #[serde(rename="sunAngle")]
   pub sun_angle: f64,
   pub waxing: bool,
+  #[serde(default)]
+  pub illumination: f64,
   pub phases: Vec<MoonPhase>
 }
 
@@ -925,6 +1393,24 @@ impl MoonData {
       phase,
       sun_angle,
       waxing,
+      illumination: illumination(sun_angle),
+      phases
+    }
+  }
+
+  /// Builds moon data from the built-in lunar model. The phase is the number of the principal
+  /// phase most recently passed, from 1 for new moon to 4 for last quarter.
+  pub fn new_local(ts: i64) -> Self {
+    let lng = moon_longitude(ts);
+    let sun_angle = sun_moon_angle(ts);
+    let phases = next_phases(ts).into_iter().map(|(num, ts)| MoonPhase { num, ts }).collect();
+    MoonData {
+      lng,
+      positions: vec![lng],
+      phase: current_phase(sun_angle),
+      sun_angle,
+      waxing: sun_angle < 180.0,
+      illumination: illumination(sun_angle),
       phases
     }
   }
@@ -940,6 +1426,29 @@ pub struct SunData {
   pub ic: Option<i64>,
   pub min: f64,
   pub max: f64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub civil: Option<Twilight>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub nautical: Option<Twilight>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub astronomical: Option<Twilight>,
+}
+
+/// Start of morning and end of evening twilight. Either is None when the sun does not
+/// cross the twilight altitude that day.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Twilight {
+  pub dawn: Option<i64>,
+  pub dusk: Option<i64>,
+}
+
+impl Twilight {
+  pub fn new(crossing: &SunCrossing) -> Self {
+    Twilight {
+      dawn: crossing.morning,
+      dusk: crossing.evening
+    }
+  }
 }
 
 impl SunData {
@@ -994,7 +1503,83 @@ impl SunData {
       mc,
       ic,
       min,
-      max
+      max,
+      civil: None,
+      nautical: None,
+      astronomical: None
+    }
+  }
+
+  /// Builds sun data from the built-in calculator, with solar noon and midnight as mc and ic
+  /// and the culmination altitudes as max and min
+  pub fn new_local(events: &SunEvents) -> Self {
+    SunData {
+      lng: events.longitude,
+      positions: vec![events.longitude],
+      rise: events.sunrise.morning,
+      set: events.sunrise.evening,
+      mc: Some(events.noon),
+      ic: Some(events.midnight),
+      min: events.min_altitude,
+      max: events.max_altitude,
+      civil: Some(Twilight::new(&events.civil)),
+      nautical: Some(Twilight::new(&events.nautical)),
+      astronomical: Some(Twilight::new(&events.astronomical))
+    }
+  }
+}
+
+/// Sun and moon events for one local calendar day. Day length is 0 during polar night
+/// and a full day while the sun stays up.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AstroDay {
+  pub date: String,
+  pub start: i64,
+  pub end: i64,
+  pub sunrise: Option<i64>,
+  pub sunset: Option<i64>,
+  #[serde(rename="solarNoon")]
+  pub solar_noon: i64,
+  #[serde(rename="dayLength")]
+  pub day_length: i64,
+  pub moonrise: Option<i64>,
+  pub moonset: Option<i64>,
+  #[serde(rename="moonPhase")]
+  pub moon_phase: u8,
+  pub illumination: f64,
+  pub waxing: bool,
+  #[serde(rename="principalPhase", skip_serializing_if = "Option::is_none")]
+  pub principal_phase: Option<MoonPhase>,
+}
+
+impl AstroDay {
+  /// Events for the local day from `start` to `end`, with the moon's phase at local noon
+  pub fn new(date: &str, geo: Geo, start: i64, end: i64, noon: i64) -> Self {
+    let sun = SunEvents::calculate(geo.lat, geo.lng, noon);
+    let day_length = match (sun.sunrise.morning, sun.sunrise.evening) {
+      (Some(rise), Some(set)) => set - rise,
+      _ if sun.min_altitude > SUNRISE_ALTITUDE => end - start,
+      _ => 0
+    };
+    let (moonrise, moonset) = moon_rise_set(geo.lat, geo.lng, start, end);
+    let sun_angle = sun_moon_angle(noon);
+    let principal_phase = next_phases(start).into_iter()
+      .find(|(_, phase_ts)| *phase_ts < end)
+      .map(|(num, ts)| MoonPhase { num, ts });
+    AstroDay {
+      date: date.to_string(),
+      start,
+      end,
+      sunrise: sun.sunrise.morning,
+      sunset: sun.sunrise.evening,
+      solar_noon: sun.noon,
+      day_length,
+      moonrise,
+      moonset,
+      moon_phase: current_phase(sun_angle),
+      illumination: illumination(sun_angle),
+      waxing: sun_angle < 180.0,
+      principal_phase
     }
   }
 }
@@ -1012,6 +1597,8 @@ pub struct AstroData {
   #[serde(rename="ageSecs",skip_serializing_if = "Option::is_none")]
   pub age_secs: Option<i64>,
   pub cached: bool,
+  #[serde(default)]
+  pub local: bool,
   #[serde(rename="currentIndex")]
   pub current_index: u16
 }
@@ -1045,10 +1632,29 @@ impl AstroData {
       ascendant,
       age_secs: None,
       cached: false,
+      local: false,
       current_index
     }
   }
 
+  /// Sun and moon data for a single moment from the built-in calculators
+  pub fn new_local(geo: Geo, ts: i64) -> Self {
+    let events = SunEvents::calculate(geo.lat, geo.lng, ts);
+    AstroData {
+      start: ts,
+      time: ts,
+      end: ts,
+      interval_secs: 0,
+      sun: Some(SunData::new_local(&events)),
+      moon: Some(MoonData::new_local(ts)),
+      ascendant: None,
+      age_secs: None,
+      cached: false,
+      local: true,
+      current_index: 0
+    }
+  }
+
   pub fn set_age(&mut self) {
     let curr_ts = Utc::now().timestamp();
     self.age_secs = Some(curr_ts - self.time);
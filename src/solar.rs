@@ -0,0 +1,155 @@
+use std::f64::consts::PI;
+use crate::common::natural_tz_offset_from_utc;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+fn to_radians(deg: f64) -> f64 {
+  deg * PI / 180.0
+}
+
+fn to_degrees(rad: f64) -> f64 {
+  rad * 180.0 / PI
+}
+
+/// Apparent position of the sun from the NOAA solar calculator formulae,
+/// accurate to about a minute of time between 1800 and 2100
+#[derive(Debug, Copy, Clone)]
+pub struct SolarPosition {
+  /// Apparent ecliptic longitude in degrees
+  pub longitude: f64,
+  /// Declination in degrees
+  pub declination: f64,
+  /// Apparent minus mean solar time in seconds
+  pub equation_of_time: f64,
+}
+
+impl SolarPosition {
+  pub fn at(ts: i64) -> Self {
+    let jc = (julian_day_converter::unixtime_to_julian_day(ts) - 2451545.0) / 36525.0;
+    let mean_long = (280.46646 + jc * (36000.76983 + jc * 0.0003032)) % 360.0;
+    let mean_anom = 357.52911 + jc * (35999.05029 - 0.0001537 * jc);
+    let eccent = 0.016708634 - jc * (0.000042037 + 0.0000001267 * jc);
+    let m_rad = to_radians(mean_anom);
+    let eq_of_ctr = m_rad.sin() * (1.914602 - jc * (0.004817 + 0.000014 * jc))
+      + (2.0 * m_rad).sin() * (0.019993 - 0.000101 * jc)
+      + (3.0 * m_rad).sin() * 0.000289;
+    let omega = to_radians(125.04 - 1934.136 * jc);
+    let longitude = (mean_long + eq_of_ctr - 0.00569 - 0.00478 * omega.sin()).rem_euclid(360.0);
+    let mean_obliq = 23.0 + (26.0 + (21.448 - jc * (46.815 + jc * (0.00059 - jc * 0.001813))) / 60.0) / 60.0;
+    let obliq = to_radians(mean_obliq + 0.00256 * omega.cos());
+    let declination = to_degrees((obliq.sin() * to_radians(longitude).sin()).asin());
+    let y = (obliq / 2.0).tan().powi(2);
+    let l_rad = to_radians(mean_long);
+    let eot_rad = y * (2.0 * l_rad).sin()
+      - 2.0 * eccent * m_rad.sin()
+      + 4.0 * eccent * y * m_rad.sin() * (2.0 * l_rad).cos()
+      - 0.5 * y * y * (4.0 * l_rad).sin()
+      - 1.25 * eccent * eccent * (2.0 * m_rad).sin();
+    SolarPosition {
+      longitude,
+      declination,
+      equation_of_time: to_degrees(eot_rad) * 4.0 * 60.0
+    }
+  }
+}
+
+/// Equation of time in whole seconds at a given moment
+pub fn equation_of_time(ts: i64) -> i64 {
+  SolarPosition::at(ts).equation_of_time.round() as i64
+}
+
+/// UTC timestamp of true solar noon on the mean solar day containing `ts`,
+/// for a location whose mean solar offset from UTC is `mean_offset` seconds
+pub fn solar_noon(ts: i64, mean_offset: i64) -> i64 {
+  let mean_noon = mean_noon(ts, mean_offset);
+  mean_noon - equation_of_time(mean_noon)
+}
+
+fn mean_noon(ts: i64, mean_offset: i64) -> i64 {
+  let day_start = (ts + mean_offset).div_euclid(DAY_SECS) * DAY_SECS;
+  day_start + DAY_SECS / 2 - mean_offset
+}
+
+/// Altitude of the sun's centre in degrees for each event, allowing for refraction
+/// and the solar disc at sunrise and sunset
+pub const SUNRISE_ALTITUDE: f64 = -0.833;
+pub const CIVIL_TWILIGHT_ALTITUDE: f64 = -6.0;
+pub const NAUTICAL_TWILIGHT_ALTITUDE: f64 = -12.0;
+pub const ASTRONOMICAL_TWILIGHT_ALTITUDE: f64 = -18.0;
+
+/// Hour angle in seconds of time at which the sun reaches the altitude, or None if it stays
+/// above or below it all day
+fn hour_angle_secs(lat: f64, declination: f64, altitude: f64) -> Option<f64> {
+  let (lat_rad, decl_rad) = (to_radians(lat), to_radians(declination));
+  let cos_h = (to_radians(altitude).sin() - lat_rad.sin() * decl_rad.sin()) / (lat_rad.cos() * decl_rad.cos());
+  if (-1.0..=1.0).contains(&cos_h) {
+    Some(to_degrees(cos_h.acos()) * 240.0)
+  } else {
+    None
+  }
+}
+
+/// Morning and evening crossings of an altitude on the mean solar day containing `ts`.
+/// Each estimate is refined twice with the sun's position at the estimated time.
+fn altitude_crossings(lat: f64, lng: f64, ts: i64, altitude: f64) -> (Option<i64>, Option<i64>) {
+  let mean_offset = natural_tz_offset_from_utc(lng);
+  let mean_noon = mean_noon(ts, mean_offset);
+  let crossing = |direction: f64| {
+    let mut event_ts = mean_noon;
+    for _ in 0..3 {
+      let position = SolarPosition::at(event_ts);
+      let hour_angle = hour_angle_secs(lat, position.declination, altitude)?;
+      event_ts = mean_noon - position.equation_of_time.round() as i64 + (direction * hour_angle).round() as i64;
+    }
+    Some(event_ts)
+  };
+  (crossing(-1.0), crossing(1.0))
+}
+
+/// Morning and evening times of the sun at an altitude
+#[derive(Debug, Copy, Clone)]
+pub struct SunCrossing {
+  pub morning: Option<i64>,
+  pub evening: Option<i64>,
+}
+
+/// Sun events for one day at a location. Culmination altitudes are in degrees.
+#[derive(Debug, Copy, Clone)]
+pub struct SunEvents {
+  pub longitude: f64,
+  pub noon: i64,
+  pub midnight: i64,
+  pub max_altitude: f64,
+  pub min_altitude: f64,
+  pub sunrise: SunCrossing,
+  pub civil: SunCrossing,
+  pub nautical: SunCrossing,
+  pub astronomical: SunCrossing,
+}
+
+impl SunEvents {
+  /// Events on the mean solar day containing `ts` at the latitude and longitude.
+  /// Solar midnight is the lower culmination following solar noon.
+  pub fn calculate(lat: f64, lng: f64, ts: i64) -> Self {
+    let mean_offset = natural_tz_offset_from_utc(lng);
+    let noon = solar_noon(ts, mean_offset);
+    // the equation of time changes by seconds at most over half a day
+    let midnight = noon + DAY_SECS / 2;
+    let declination = SolarPosition::at(noon).declination;
+    let crossing = |altitude: f64| {
+      let (morning, evening) = altitude_crossings(lat, lng, ts, altitude);
+      SunCrossing { morning, evening }
+    };
+    SunEvents {
+      longitude: SolarPosition::at(ts).longitude,
+      noon,
+      midnight,
+      max_altitude: 90.0 - (lat - declination).abs(),
+      min_altitude: (lat + declination).abs() - 90.0,
+      sunrise: crossing(SUNRISE_ALTITUDE),
+      civil: crossing(CIVIL_TWILIGHT_ALTITUDE),
+      nautical: crossing(NAUTICAL_TWILIGHT_ALTITUDE),
+      astronomical: crossing(ASTRONOMICAL_TWILIGHT_ALTITUDE)
+    }
+  }
+}
@@ -0,0 +1,58 @@
+use axum::extract::FromRef;
+use mongodb::Client;
+
+use crate::{gazetteer::SharedGazetteer, store::SharedStore, timezones::SharedTzIndex, upstream::SharedUpstream};
+
+/// Shared application state. Handlers may extract the Mongo `Client`, the cache
+/// `SharedStore`, the `SharedUpstream` HTTP client, the `SharedTzIndex` or the `SharedGazetteer`
+/// on its own via `State<T>`.
+#[derive(Clone)]
+pub struct AppState {
+  pub client: Client,
+  pub store: SharedStore,
+  pub upstream: SharedUpstream,
+  pub tz_index: SharedTzIndex,
+  pub gazetteer: SharedGazetteer,
+}
+
+impl AppState {
+  pub fn new(client: Client, store: SharedStore, upstream: SharedUpstream, tz_index: SharedTzIndex, gazetteer: SharedGazetteer) -> Self {
+    AppState {
+      client,
+      store,
+      upstream,
+      tz_index,
+      gazetteer
+    }
+  }
+}
+
+impl FromRef<AppState> for Client {
+  fn from_ref(state: &AppState) -> Self {
+    state.client.clone()
+  }
+}
+
+impl FromRef<AppState> for SharedStore {
+  fn from_ref(state: &AppState) -> Self {
+    state.store.clone()
+  }
+}
+
+impl FromRef<AppState> for SharedUpstream {
+  fn from_ref(state: &AppState) -> Self {
+    state.upstream.clone()
+  }
+}
+
+impl FromRef<AppState> for SharedTzIndex {
+  fn from_ref(state: &AppState) -> Self {
+    state.tz_index.clone()
+  }
+}
+
+impl FromRef<AppState> for SharedGazetteer {
+  fn from_ref(state: &AppState) -> Self {
+    state.gazetteer.clone()
+  }
+}
\ No newline at end of file
@@ -1,165 +1,504 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use async_trait::async_trait;
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::RwLock;
 
-use redis::{Commands, Connection, RedisResult};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::{common::get_cache_backend, db::CacheConfig, errors::{GeoFinderError, GeoFinderResult}, models::{AstroData, AstroDay, GeoNearby, PcLookup, PcRow, PcZone, PlaceOfInterest, PlaceRow, TzRow, WeatherReport, WikipediaSummary}};
 
-use crate::{models::{AstroData, GeoNearby, PcRow, PcZone, PlaceOfInterest, PlaceRow, TzRow, WeatherReport, WikipediaSummary}, store};
+/// Key/value cache backend holding serialised JSON strings.
+/// Typed get/set helpers are implemented on `dyn CacheStore` below.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+  async fn get_string(&self, key: &str) -> Option<String>;
 
-pub(crate) fn redis_client() -> RedisResult<Connection> {
-  let client = redis::Client::open("redis://127.0.0.1/")?;
-  client.get_connection()
+  /// An expiry of 0 seconds stores the value without a TTL
+  async fn set_string(&self, key: &str, value: String, expiry: usize) -> bool;
+
+  /// Values for several keys in one round trip, in key order
+  async fn get_strings_many(&self, keys: &[String]) -> Vec<Option<String>>;
+
+  /// Stores several values with the same expiry in one round trip
+  async fn set_strings_many(&self, entries: Vec<(String, String)>, expiry: usize) -> bool;
+
+  /// Remaining seconds to live, -1 if the key has no expiry and None if the key does not exist
+  async fn ttl(&self, key: &str) -> Option<i64>;
+
+  async fn delete(&self, key: &str) -> bool;
+
+  async fn health(&self) -> StoreHealth;
 }
 
-pub(crate) fn redis_get_opt_string<'a>(key: &str) -> Option<String> {
-  if let Ok(mut connection) =  redis_client() {
-    if let Ok(result) = connection.get(key.to_owned()) {
-      return Some(result);
-    }
-  }
-  None
+pub type SharedStore = Arc<dyn CacheStore>;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct StoreHealth {
+  pub backend: String,
+  pub connected: bool,
+  #[serde(rename="latencyMs",skip_serializing_if = "Option::is_none")]
+  pub latency_ms: Option<u64>,
 }
 
-pub(crate) fn redis_set_pc_results(key: &str, data: &Vec<PcRow>) -> bool {
-  redis_set_data::<Vec<PcRow>>(key, data, 0)
+impl StoreHealth {
+  pub fn new(backend: &str, connected: bool, latency_ms: Option<u64>) -> Self {
+    StoreHealth {
+      backend: backend.to_string(),
+      connected,
+      latency_ms
+    }
+  }
 }
 
-pub fn redis_get_pc_results(key: &str) -> Vec<PcRow> {
-  redis_get_data::<Vec<PcRow>>(key).unwrap_or(vec![])
+pub async fn build_store(config: &CacheConfig) -> SharedStore {
+  match get_cache_backend().as_str() {
+    "memory" => Arc::new(MemoryStore::new()),
+    _ => Arc::new(RedisStore::connect(config).await)
+  }
 }
 
+/// Limit on establishing the first Redis connection so requests never wait on an unreachable server
+const REDIS_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
 
+/// Calls within this period after a failed connection attempt fail at once without reconnecting
+const REDIS_RECONNECT_COOLDOWN: Duration = Duration::from_secs(15);
 
-pub(crate) fn redis_set_pc_zones(key: &str, data: &Vec<PcZone>) -> bool {
-  redis_set_data::<Vec<PcZone>>(key, data, 0)
+/// Redis backend sharing one multiplexed connection across all requests.
+/// The connection manager reconnects on its own once established; if Redis is
+/// unreachable at startup a later call will establish it once the cooldown has passed.
+pub struct RedisStore {
+  client: Option<redis::Client>,
+  manager: RwLock<Option<ConnectionManager>>,
+  last_failure: Mutex<Option<Instant>>
 }
 
-pub fn redis_get_pc_zones(key: &str) -> Vec<PcZone> {
-  redis_get_data::<Vec<PcZone>>(key).unwrap_or(vec![])
-}
+impl RedisStore {
+  pub async fn connect(config: &CacheConfig) -> Self {
+    let client = config.to_connection_info().and_then(redis::Client::open).ok();
+    let store = RedisStore {
+      client,
+      manager: RwLock::new(None),
+      last_failure: Mutex::new(None)
+    };
+    if store.connection().await.is_err() {
+      tracing::warn!("redis unavailable at {}", config.uri);
+    }
+    store
+  }
 
+  fn in_cooldown(&self) -> bool {
+    self.last_failure.lock().ok()
+      .and_then(|failed| *failed)
+      .map(|failed_at| failed_at.elapsed() < REDIS_RECONNECT_COOLDOWN)
+      .unwrap_or(false)
+  }
 
-pub fn  redis_set_geo_nearby(key: &str, data: &GeoNearby) -> bool {
-  redis_set_data::<GeoNearby>(key, data, 0)
-}
+  fn unavailable() -> GeoFinderError {
+    GeoFinderError::StorageFailure("redis is unavailable".to_string())
+  }
 
-pub fn redis_get_geo_nearby(key: &str) -> Option<GeoNearby> {
-  redis_get_data::<GeoNearby>(key)
+  async fn connection(&self) -> GeoFinderResult<ConnectionManager> {
+    if let Some(manager) = self.manager.read().await.clone() {
+      return Ok(manager);
+    }
+    if self.in_cooldown() {
+      return Err(Self::unavailable());
+    }
+    let client = self.client.clone().ok_or_else(Self::unavailable)?;
+    let mut manager = self.manager.write().await;
+    // another call may have connected or failed while this one waited for the lock
+    if manager.is_none() && !self.in_cooldown() {
+      match tokio::time::timeout(REDIS_CONNECT_TIMEOUT, ConnectionManager::new(client)).await {
+        Ok(Ok(connected)) => *manager = Some(connected),
+        _ => {
+          if let Ok(mut failed) = self.last_failure.lock() {
+            *failed = Some(Instant::now());
+          }
+        }
+      }
+    }
+    manager.clone().ok_or_else(Self::unavailable)
+  }
 }
 
-pub fn redis_set_timezone(key: &str, data: &TzRow) -> bool {
-  let expiry = 15 * 60;
-  redis_set_data::<TzRow>(key, data, expiry)
-}
+#[async_trait]
+impl CacheStore for RedisStore {
+  async fn get_string(&self, key: &str) -> Option<String> {
+    let mut connection = self.connection().await.ok()?;
+    connection.get::<&str, Option<String>>(key).await.ok().flatten()
+  }
 
-pub fn redis_get_timezone(key: &str) -> Option<TzRow> {
-  redis_get_data::<TzRow>(key)
-}
+  async fn set_string(&self, key: &str, value: String, expiry: usize) -> bool {
+    if let Ok(mut connection) = self.connection().await {
+      let stored = if expiry > 0  {
+        connection.set_ex::<&str, String, ()>(key, value, expiry).await
+      } else {
+        connection.set::<&str, String, ()>(key, value).await
+      };
+      return stored.is_ok();
+    }
+    false
+  }
 
-pub fn  redis_set_strings(key: &str, data: &Vec<String>) -> bool {
-  redis_set_data::<Vec<String>>(key, data, 0)
-}
+  async fn get_strings_many(&self, keys: &[String]) -> Vec<Option<String>> {
+    if keys.is_empty() {
+      return vec![];
+    }
+    if let Ok(mut connection) = self.connection().await {
+      // MGET directly, as the typed helper sends GET for a single key
+      let values = redis::cmd("MGET").arg(keys).query_async::<_, Vec<Option<String>>>(&mut connection).await;
+      if let Ok(values) = values {
+        return values;
+      }
+    }
+    vec![None; keys.len()]
+  }
 
-pub fn redis_get_strings(key: &str) -> Option<Vec<String>> {
-  redis_get_data::<Vec<String>>(key)
-}
+  async fn set_strings_many(&self, entries: Vec<(String, String)>, expiry: usize) -> bool {
+    if entries.is_empty() {
+      return true;
+    }
+    if let Ok(mut connection) = self.connection().await {
+      let mut pipe = redis::pipe();
+      for (key, value) in entries {
+        if expiry > 0 {
+          pipe.set_ex(key, value, expiry).ignore();
+        } else {
+          pipe.set(key, value).ignore();
+        }
+      }
+      return pipe.query_async::<_, ()>(&mut connection).await.is_ok();
+    }
+    false
+  }
 
-pub fn  redis_set_weather(key: &str, data: &WeatherReport) -> bool {
-  let expiry = 30 * 60;
-  redis_set_data::<WeatherReport>(key, data, expiry)
-}
+  async fn ttl(&self, key: &str) -> Option<i64> {
+    let mut connection = self.connection().await.ok()?;
+    let secs = connection.ttl::<&str, i64>(key).await.ok()?;
+    // redis returns -2 for missing keys
+    if secs >= -1 {
+      Some(secs)
+    } else {
+      None
+    }
+  }
 
-pub fn redis_get_weather(key: &str) -> Option<WeatherReport> {
-  redis_get_data::<WeatherReport>(key)
-}
+  async fn delete(&self, key: &str) -> bool {
+    if let Ok(mut connection) = self.connection().await {
+      if let Ok(num) = connection.del::<&str, u32>(key).await {
+        return num > 0;
+      }
+    }
+    false
+  }
 
-pub fn  redis_set_poi(key: &str, data: &Vec<PlaceOfInterest>) -> bool {
-  let expiry = 31 * 24 * 60 * 60;
-  redis_set_data::<Vec<PlaceOfInterest>>(key, data, expiry)
+  async fn health(&self) -> StoreHealth {
+    if let Ok(mut connection) = self.connection().await {
+      let start = Instant::now();
+      let pong = redis::cmd("PING").query_async::<_, String>(&mut connection).await;
+      if pong.is_ok() {
+        return StoreHealth::new("redis", true, Some(start.elapsed().as_millis() as u64));
+      }
+    }
+    StoreHealth::new("redis", false, None)
+  }
 }
 
-pub fn redis_get_poi(key: &str) -> Option<Vec<PlaceOfInterest>> {
-  redis_get_data::<Vec<PlaceOfInterest>>(key)
+struct MemoryEntry {
+  value: String,
+  expires: Option<Instant>
 }
 
-pub fn  redis_set_postcode(key: &str, data: &PcZone) -> bool {
-  let expiry = 31 * 24 * 60 * 60;
-  redis_set_data::<PcZone>(key, data, expiry)
+impl MemoryEntry {
+  fn is_expired(&self) -> bool {
+    self.expires.map(|exp| exp <= Instant::now()).unwrap_or(false)
+  }
 }
 
-pub fn redis_get_postcode(key: &str) -> Option<PcZone> {
-  redis_get_data::<PcZone>(key)
-}
+/// Expired entries are swept after this many writes
+const MEMORY_SWEEP_INTERVAL: usize = 1000;
 
-pub fn  redis_set_wiki_summaries(key: &str, data: &Vec<WikipediaSummary>) -> bool {
-  let expiry = 3 * 31 * 24 * 60 * 60;
-  redis_set_data::<Vec<WikipediaSummary>>(key, data, expiry)
-}
+/// Upper bound on entries held in memory. Beyond it the entries closest to expiry are evicted,
+/// then those without a TTL, down to 90% of the limit so a full store is not sorted on every write.
+const MAX_MEMORY_ENTRIES: usize = 100_000;
 
-pub fn redis_get_wiki_summaries(key: &str) -> Option<Vec<WikipediaSummary>> {
-  redis_get_data::<Vec<WikipediaSummary>>(key)
+#[derive(Default)]
+struct MemoryEntries {
+  entries: HashMap<String, MemoryEntry>,
+  writes: usize,
 }
 
-pub fn redis_set_astro_data(key: &str, data: &AstroData) -> bool {
-  // only 30 minutes
-  let expiry = 30 * 60;
-  redis_set_data::<AstroData>(key, data, expiry)
-}
+impl MemoryEntries {
+  fn get(&mut self, key: &str) -> Option<String> {
+    match self.entries.get(key) {
+      Some(entry) if entry.is_expired() => {
+        self.entries.remove(key);
+        None
+      },
+      Some(entry) => Some(entry.value.clone()),
+      None => None
+    }
+  }
 
-pub fn redis_get_astro_data(key: &str) -> Option<AstroData> {
-  redis_get_data::<AstroData>(key)
-}
+  fn insert(&mut self, key: String, value: String, expiry: usize) {
+    let expires = if expiry > 0 {
+      Some(Instant::now() + Duration::from_secs(expiry as u64))
+    } else {
+      None
+    };
+    self.entries.insert(key, MemoryEntry { value, expires });
+    self.writes += 1;
+    if self.writes.is_multiple_of(MEMORY_SWEEP_INTERVAL) || self.entries.len() > MAX_MEMORY_ENTRIES {
+      self.sweep();
+    }
+  }
 
-pub fn redis_set_place_rows(key: &str, data: &Vec<PlaceRow>) -> bool {
-  // store for a month
-  let expiry = 30 * 24 * 60 * 60;
-  redis_set_data::<Vec<PlaceRow>>(key, data, expiry)
+  fn sweep(&mut self) {
+    self.entries.retain(|_, entry| !entry.is_expired());
+    if self.entries.len() > MAX_MEMORY_ENTRIES {
+      let mut by_expiry: Vec<(Option<Instant>, String)> = self.entries.iter()
+        .map(|(key, entry)| (entry.expires, key.clone()))
+        .collect();
+      // None sorts first, so order entries without a TTL after all expiring ones
+      by_expiry.sort_by_key(|(expires, _)| (expires.is_none(), *expires));
+      let excess = self.entries.len() - MAX_MEMORY_ENTRIES * 9 / 10;
+      for (_, key) in by_expiry.into_iter().take(excess) {
+        self.entries.remove(&key);
+      }
+    }
+  }
 }
 
-pub fn redis_get_place_rows(key: &str) -> Option<Vec<PlaceRow>> {
-  redis_get_data::<Vec<PlaceRow>>(key)
+/// In-process store for tests and small deployments without Redis.
+/// Expired entries are evicted when read and swept periodically as new entries are written.
+#[derive(Default)]
+pub struct MemoryStore {
+  entries: Mutex<MemoryEntries>
 }
 
-pub fn redis_set_data_checked(key: &str, days: usize) -> bool {
-  let expiry = days * 24 * 60 * 60;
-  redis_set_data::<u8>(&key, &1, expiry)
+impl MemoryStore {
+  pub fn new() -> Self {
+    MemoryStore::default()
+  }
 }
 
-pub fn redis_data_have_been_checked(key: &str) -> bool {
-  let stored = redis_get_data::<u8>(&key);
-  stored.is_some()
-}
+#[async_trait]
+impl CacheStore for MemoryStore {
+  async fn get_string(&self, key: &str) -> Option<String> {
+    self.entries.lock().ok()?.get(key)
+  }
 
-pub fn redis_set_addresses_checked(pc: &str) -> bool {
-  let key = format!("address_check_{}", pc.replace(" ", "_"));
-  redis_set_data_checked(&key, 183)
-}
+  async fn set_string(&self, key: &str, value: String, expiry: usize) -> bool {
+    if let Ok(mut store) = self.entries.lock() {
+      store.insert(key.to_string(), value, expiry);
+      return true;
+    }
+    false
+  }
 
-pub fn redis_addresses_have_been_checked(pc: &str) -> bool {
-  let key = format!("address_check_{}", pc.replace(" ", "_"));
-  redis_data_have_been_checked(&key)
-}
+  async fn get_strings_many(&self, keys: &[String]) -> Vec<Option<String>> {
+    match self.entries.lock() {
+      Ok(mut store) => keys.iter().map(|key| store.get(key)).collect(),
+      Err(_) => vec![None; keys.len()]
+    }
+  }
 
-pub fn redis_get_data<T>(key: &str) -> Option<T>
-  where T: DeserializeOwned + Serialize {
-  if let Some(result) = redis_get_opt_string(key) {
-    if let Ok(items) = serde_json::from_str::<T>(&result) {
-      return Some(items);
+  async fn set_strings_many(&self, entries: Vec<(String, String)>, expiry: usize) -> bool {
+    if let Ok(mut store) = self.entries.lock() {
+      for (key, value) in entries {
+        store.insert(key, value, expiry);
+      }
+      return true;
     }
+    false
+  }
+
+  async fn ttl(&self, key: &str) -> Option<i64> {
+    let store = self.entries.lock().ok()?;
+    let entry = store.entries.get(key).filter(|entry| !entry.is_expired())?;
+    match entry.expires {
+      Some(exp) => Some(exp.saturating_duration_since(Instant::now()).as_secs() as i64),
+      None => Some(-1)
+    }
+  }
+
+  async fn delete(&self, key: &str) -> bool {
+    if let Ok(mut store) = self.entries.lock() {
+      return store.entries.remove(key).is_some();
+    }
+    false
+  }
+
+  async fn health(&self) -> StoreHealth {
+    StoreHealth::new("memory", self.entries.lock().is_ok(), Some(0))
   }
-  None
 }
 
-pub fn  redis_set_data<'a, T:Serialize + Deserialize<'a>>(key: &str, data: &T, expiry: usize) -> bool {
-  if let Ok(mut connection) =  redis_client() {
-    if let Ok(value) = serde_json::to_string(&data) {
-      let stored = if expiry > 0  {
-        connection.set_ex::<String,String,String>(key.to_string(), value, expiry)
-      } else {
-        connection.set::<String,String,String>(key.to_string(), value)
-      };
-      if let Ok(_result) = stored {
-          return true;
+impl dyn CacheStore + '_ {
+  pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+    if let Some(result) = self.get_string(key).await {
+      if let Ok(items) = serde_json::from_str::<T>(&result) {
+        return Some(items);
       }
     }
+    None
+  }
+
+  pub async fn set<T: Serialize + Sync + ?Sized>(&self, key: &str, data: &T, expiry: usize) -> bool {
+    if let Ok(value) = serde_json::to_string(data) {
+      return self.set_string(key, value, expiry).await;
+    }
+    false
+  }
+
+  pub async fn set_pc_results(&self, key: &str, data: &Vec<PcRow>) -> bool {
+    self.set::<Vec<PcRow>>(key, data, 0).await
+  }
+
+  pub async fn get_pc_results(&self, key: &str) -> Vec<PcRow> {
+    self.get::<Vec<PcRow>>(key).await.unwrap_or(vec![])
+  }
+
+  pub async fn set_pc_zones(&self, key: &str, data: &Vec<PcZone>) -> bool {
+    self.set::<Vec<PcZone>>(key, data, 0).await
+  }
+
+  pub async fn get_pc_zones(&self, key: &str) -> Vec<PcZone> {
+    self.get::<Vec<PcZone>>(key).await.unwrap_or(vec![])
+  }
+
+  pub async fn set_geo_nearby(&self, key: &str, data: &GeoNearby) -> bool {
+    self.set::<GeoNearby>(key, data, 0).await
+  }
+
+  pub async fn get_geo_nearby(&self, key: &str) -> Option<GeoNearby> {
+    self.get::<GeoNearby>(key).await
+  }
+
+  pub async fn set_timezone(&self, key: &str, data: &TzRow) -> bool {
+    let expiry = 15 * 60;
+    self.set::<TzRow>(key, data, expiry).await
+  }
+
+  pub async fn get_timezone(&self, key: &str) -> Option<TzRow> {
+    self.get::<TzRow>(key).await
+  }
+
+  pub async fn set_strings(&self, key: &str, data: &Vec<String>) -> bool {
+    self.set::<Vec<String>>(key, data, 0).await
+  }
+
+  pub async fn get_strings(&self, key: &str) -> Option<Vec<String>> {
+    self.get::<Vec<String>>(key).await
+  }
+
+  pub async fn set_weather(&self, key: &str, data: &WeatherReport) -> bool {
+    let expiry = 30 * 60;
+    self.set::<WeatherReport>(key, data, expiry).await
+  }
+
+  pub async fn get_weather(&self, key: &str) -> Option<WeatherReport> {
+    self.get::<WeatherReport>(key).await
+  }
+
+  pub async fn set_poi(&self, key: &str, data: &Vec<PlaceOfInterest>) -> bool {
+    let expiry = 31 * 24 * 60 * 60;
+    self.set::<Vec<PlaceOfInterest>>(key, data, expiry).await
+  }
+
+  pub async fn get_poi(&self, key: &str) -> Option<Vec<PlaceOfInterest>> {
+    self.get::<Vec<PlaceOfInterest>>(key).await
   }
-  false
-}
\ No newline at end of file
+
+  pub async fn set_postcode(&self, key: &str, data: &PcZone) -> bool {
+    let expiry = 31 * 24 * 60 * 60;
+    self.set::<PcZone>(key, data, expiry).await
+  }
+
+  pub async fn get_postcode(&self, key: &str) -> Option<PcZone> {
+    self.get::<PcZone>(key).await
+  }
+
+  pub async fn set_pc_lookup(&self, key: &str, data: &PcLookup) -> bool {
+    let expiry = 31 * 24 * 60 * 60;
+    self.set::<PcLookup>(key, data, expiry).await
+  }
+
+  pub async fn get_pc_lookup(&self, key: &str) -> Option<PcLookup> {
+    self.get::<PcLookup>(key).await
+  }
+
+  pub async fn set_pc_prefix_matches(&self, key: &str, data: &Vec<String>) -> bool {
+    // postcodes are only added or terminated in monthly releases
+    let expiry = 7 * 24 * 60 * 60;
+    self.set::<Vec<String>>(key, data, expiry).await
+  }
+
+  pub async fn get_pc_prefix_matches(&self, key: &str) -> Option<Vec<String>> {
+    self.get::<Vec<String>>(key).await
+  }
+
+  pub async fn set_wiki_summaries(&self, key: &str, data: &Vec<WikipediaSummary>) -> bool {
+    let expiry = 3 * 31 * 24 * 60 * 60;
+    self.set::<Vec<WikipediaSummary>>(key, data, expiry).await
+  }
+
+  pub async fn get_wiki_summaries(&self, key: &str) -> Option<Vec<WikipediaSummary>> {
+    self.get::<Vec<WikipediaSummary>>(key).await
+  }
+
+  pub async fn set_astro_data(&self, key: &str, data: &AstroData) -> bool {
+    // only 30 minutes
+    let expiry = 30 * 60;
+    self.set::<AstroData>(key, data, expiry).await
+  }
+
+  pub async fn get_astro_data(&self, key: &str) -> Option<AstroData> {
+    self.get::<AstroData>(key).await
+  }
+
+  pub async fn set_astro_days(&self, entries: &[(String, AstroDay)]) -> bool {
+    // events for a past or future date never change
+    let expiry = 31 * 24 * 60 * 60;
+    let values = entries.iter()
+      .filter_map(|(key, day)| serde_json::to_string(day).ok().map(|value| (key.clone(), value)))
+      .collect();
+    self.set_strings_many(values, expiry).await
+  }
+
+  /// Cached days for each key in order, None where missing
+  pub async fn get_astro_days(&self, keys: &[String]) -> Vec<Option<AstroDay>> {
+    self.get_strings_many(keys).await.into_iter()
+      .map(|value| value.and_then(|json| serde_json::from_str::<AstroDay>(&json).ok()))
+      .collect()
+  }
+
+  pub async fn set_place_rows(&self, key: &str, data: &Vec<PlaceRow>) -> bool {
+    // store for a month
+    let expiry = 30 * 24 * 60 * 60;
+    self.set::<Vec<PlaceRow>>(key, data, expiry).await
+  }
+
+  pub async fn get_place_rows(&self, key: &str) -> Option<Vec<PlaceRow>> {
+    self.get::<Vec<PlaceRow>>(key).await
+  }
+
+  pub async fn set_data_checked(&self, key: &str, days: usize) -> bool {
+    let expiry = days * 24 * 60 * 60;
+    self.set::<u8>(key, &1, expiry).await
+  }
+
+  pub async fn data_have_been_checked(&self, key: &str) -> bool {
+    let stored = self.get::<u8>(key).await;
+    stored.is_some()
+  }
+
+  pub async fn set_addresses_checked(&self, pc: &str) -> bool {
+    let key = format!("address_check_{}", pc.replace(" ", "_"));
+    self.set_data_checked(&key, 183).await
+  }
+
+  pub async fn addresses_have_been_checked(&self, pc: &str) -> bool {
+    let key = format!("address_check_{}", pc.replace(" ", "_"));
+    self.data_have_been_checked(&key).await
+  }
+}
@@ -0,0 +1,328 @@
+use std::{fs, sync::Arc};
+use chrono::{DateTime, Datelike, Days, NaiveDate};
+use rstar::{RTree, RTreeObject, AABB};
+use serde_json::{Map, Value};
+use tz::{timezone::{AlternateTime, RuleDay, TransitionRule}, LocalTimeType};
+
+use crate::{common::get_tz_boundaries_file, models::{Geo, TzPeriod, TzRow, TzTransition}};
+
+/// UTC offset in seconds, DST flag and abbreviation in force at a given moment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneOffset {
+  pub offset: i64,
+  pub dst: bool,
+  pub abbreviation: String,
+}
+
+impl ZoneOffset {
+  fn new(local_type: &LocalTimeType) -> Self {
+    ZoneOffset {
+      offset: local_type.ut_offset() as i64,
+      dst: local_type.is_dst(),
+      abbreviation: local_type.time_zone_designation().to_string()
+    }
+  }
+}
+
+pub fn zone_offset_at(zone_name: &str, ts: i64) -> Option<ZoneOffset> {
+  let tz = tzdb::tz_by_name(zone_name)?;
+  tz.find_local_time_type(ts).ok().map(ZoneOffset::new)
+}
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// First moment after `ts` when the offset, DST flag or abbreviation changes, read from the zone's
+/// transition table and, after its last entry, from the recurring daylight saving rule
+pub fn next_transition(zone_name: &str, ts: i64) -> Option<(i64, ZoneOffset)> {
+  let tz = tzdb::tz_by_name(zone_name)?;
+  let current = ZoneOffset::new(tz.find_local_time_type(ts).ok()?);
+  let transitions = tz.transitions();
+  let local_types = tz.local_time_types();
+  let start = transitions.partition_point(|tr| tr.unix_leap_time() <= ts);
+  // some entries only change the zone's internal type index
+  for transition in &transitions[start..] {
+    let next = ZoneOffset::new(local_types.get(transition.local_time_type_index())?);
+    if next != current {
+      return Some((transition.unix_leap_time(), next));
+    }
+  }
+  let after = transitions.last().map(|tr| tr.unix_leap_time().max(ts)).unwrap_or(ts);
+  match tz.extra_rule() {
+    Some(TransitionRule::Alternate(rule)) => next_rule_transition(rule, after, &current),
+    _ => None
+  }
+}
+
+/// Next change after `ts` from a recurring rule such as Europe's last Sundays of March and October
+fn next_rule_transition(rule: &AlternateTime, ts: i64, current: &ZoneOffset) -> Option<(i64, ZoneOffset)> {
+  let year = DateTime::from_timestamp(ts, 0)?.year();
+  let mut changes: Vec<(i64, ZoneOffset)> = vec![];
+  for y in year..=year + 1 {
+    // rule times are local, before the change
+    let start = rule_day_ts(rule.dst_start(), y)? + rule.dst_start_time() as i64 - rule.std().ut_offset() as i64;
+    let end = rule_day_ts(rule.dst_end(), y)? + rule.dst_end_time() as i64 - rule.dst().ut_offset() as i64;
+    changes.push((start, ZoneOffset::new(rule.dst())));
+    changes.push((end, ZoneOffset::new(rule.std())));
+  }
+  changes.sort_by_key(|(change_ts, _)| *change_ts);
+  changes.into_iter().find(|(change_ts, next)| *change_ts > ts && next != current)
+}
+
+/// Midnight UTC at the start of a rule day in a year
+fn rule_day_ts(rule_day: &RuleDay, year: i32) -> Option<i64> {
+  let date = match rule_day {
+    // days 1 to 365, never counting 29 February
+    RuleDay::Julian1WithoutLeap(day) => {
+      let common = NaiveDate::from_yo_opt(2001, day.get() as u32)?;
+      NaiveDate::from_ymd_opt(year, common.month(), common.day())?
+    },
+    // days 0 to 365 counting 29 February, where day 365 of a common year is 1 January
+    RuleDay::Julian0WithLeap(day) => NaiveDate::from_ymd_opt(year, 1, 1)?.checked_add_days(Days::new(day.get() as u64))?,
+    // week 5 is the last occurrence of the weekday in the month
+    RuleDay::MonthWeekDay(mwd) => {
+      let first = NaiveDate::from_ymd_opt(year, mwd.month() as u32, 1)?;
+      let offset = (mwd.week_day() as i64 - first.weekday().num_days_from_sunday() as i64).rem_euclid(7) + (mwd.week() as i64 - 1) * 7;
+      let mut date = first.checked_add_days(Days::new(offset as u64))?;
+      while date.month() != first.month() {
+        date = date.checked_sub_days(Days::new(7))?;
+      }
+      date
+    }
+  };
+  Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp())
+}
+
+/// UTC instant for a local wall-clock time. Times repeated when clocks go back are ambiguous,
+/// with both instants earliest first, and times skipped when clocks go forward are nonexistent.
+#[derive(Debug, Copy, Clone)]
+pub enum LocalTimeMatch {
+  Unique(i64),
+  Ambiguous(i64, i64),
+  Nonexistent(i64),
+}
+
+impl LocalTimeMatch {
+  /// The earlier instant for ambiguous times. Nonexistent times are read with the offset
+  /// before the gap, so 02:30 on a spring-forward night becomes 03:30 local time.
+  pub fn utc_ts(&self) -> i64 {
+    match self {
+      Self::Unique(ts) | Self::Ambiguous(ts, _) | Self::Nonexistent(ts) => *ts
+    }
+  }
+}
+
+/// Resolves a local time, given as seconds since the epoch as if it were UTC, in a zone
+pub fn resolve_local_time(zone_name: &str, local_ts: i64) -> Option<LocalTimeMatch> {
+  let before = zone_offset_at(zone_name, local_ts - DAY_SECS)?.offset;
+  let mut offsets = vec![
+    before,
+    zone_offset_at(zone_name, local_ts)?.offset,
+    zone_offset_at(zone_name, local_ts + DAY_SECS)?.offset
+  ];
+  offsets.sort();
+  offsets.dedup();
+  let mut candidates: Vec<i64> = offsets.into_iter()
+    .map(|offset| local_ts - offset)
+    .filter(|utc_ts| zone_offset_at(zone_name, *utc_ts).map(|current| local_ts - current.offset == *utc_ts).unwrap_or(false))
+    .collect();
+  candidates.sort();
+  Some(match candidates.as_slice() {
+    [] => LocalTimeMatch::Nonexistent(local_ts - before),
+    [utc_ts] => LocalTimeMatch::Unique(*utc_ts),
+    [first, .., last] => LocalTimeMatch::Ambiguous(*first, *last)
+  })
+}
+
+/// Upper bound on transitions listed for one range
+pub const MAX_TRANSITIONS: usize = 500;
+
+/// All transitions between two timestamps, or None if the zone is unknown.
+/// Each period ends at the following transition, including the first one after the range.
+pub fn list_transitions(zone_name: &str, start_ts: i64, end_ts: i64) -> Option<Vec<TzTransition>> {
+  let mut current = zone_offset_at(zone_name, start_ts)?;
+  let mut transitions: Vec<TzTransition> = vec![];
+  let mut ts = start_ts;
+  while ts < end_ts && transitions.len() < MAX_TRANSITIONS {
+    match next_transition(zone_name, ts) {
+      Some((at, next)) if at <= end_ts => {
+        transitions.push(TzTransition::new(at, &current, &next));
+        current = next;
+        ts = at;
+      },
+      // past the range, or no further changes are scheduled
+      _ => break
+    }
+  }
+  let ends: Vec<Option<i64>> = transitions.iter().skip(1).map(|tr| tr.period.start).collect();
+  for (transition, end) in transitions.iter_mut().zip(ends) {
+    transition.period.end = end;
+  }
+  if let Some(last) = transitions.last_mut() {
+    last.period.end = next_transition(zone_name, ts).map(|(at, _)| at);
+  }
+  Some(transitions)
+}
+
+pub fn is_known_zone(zone_name: &str) -> bool {
+  tzdb::tz_by_name(zone_name).is_some()
+}
+
+/// The period following `ts`, starting at the next transition and ending at the one after
+pub fn build_next_period(zone_name: &str, ts: i64) -> Option<TzPeriod> {
+  next_transition(zone_name, ts).map(|(start, next)| {
+    let end = next_transition(zone_name, start).map(|(end, _)| end);
+    TzPeriod {
+      start: Some(start),
+      end,
+      next_gmt_offset: Some(next.offset)
+    }
+  })
+}
+
+/// Current and next period for a zone computed from the bundled IANA tz database
+pub fn build_local_tz_row(zone_name: &str, ts: i64) -> Option<TzRow> {
+  let current = zone_offset_at(zone_name, ts)?;
+  let period = build_next_period(zone_name, ts);
+  Some(TzRow::new_local(zone_name, ts, &current, period))
+}
+
+/// One polygon of a timezone boundary in [lng, lat] order with its holes
+struct ZonePolygon {
+  zone_name: Arc<str>,
+  exterior: Vec<[f64; 2]>,
+  holes: Vec<Vec<[f64; 2]>>,
+  envelope: AABB<[f64; 2]>,
+}
+
+impl RTreeObject for ZonePolygon {
+  type Envelope = AABB<[f64; 2]>;
+
+  fn envelope(&self) -> Self::Envelope {
+    self.envelope
+  }
+}
+
+impl ZonePolygon {
+  fn new(zone_name: Arc<str>, rings: Vec<Vec<[f64; 2]>>) -> Option<Self> {
+    let mut rings = rings.into_iter();
+    let exterior = rings.next().filter(|ring| ring.len() > 3)?;
+    let envelope = AABB::from_points(exterior.iter());
+    Some(ZonePolygon {
+      zone_name,
+      exterior,
+      // malformed hole rings cannot enclose anything
+      holes: rings.filter(|ring| ring.len() >= 3).collect(),
+      envelope
+    })
+  }
+
+  fn contains(&self, point: [f64; 2]) -> bool {
+    ring_contains(&self.exterior, point) && !self.holes.iter().any(|hole| ring_contains(hole, point))
+  }
+}
+
+/// Even-odd ray casting test. Rings with fewer than 3 points contain nothing.
+fn ring_contains(ring: &[[f64; 2]], point: [f64; 2]) -> bool {
+  if ring.len() < 3 {
+    return false;
+  }
+  let [x, y] = point;
+  let mut inside = false;
+  let mut prev = ring[ring.len() - 1];
+  for &curr in ring {
+    if (curr[1] > y) != (prev[1] > y) && x < (prev[0] - curr[0]) * (y - curr[1]) / (prev[1] - curr[1]) + curr[0] {
+      inside = !inside;
+    }
+    prev = curr;
+  }
+  inside
+}
+
+fn parse_ring(value: &Value) -> Vec<[f64; 2]> {
+  value.as_array().map(|points| {
+    points.iter().filter_map(|point| {
+      let coords = point.as_array()?;
+      Some([coords.first()?.as_f64()?, coords.get(1)?.as_f64()?])
+    }).collect()
+  }).unwrap_or_default()
+}
+
+fn parse_polygon(value: &Value) -> Vec<Vec<[f64; 2]>> {
+  value.as_array().map(|rings| rings.iter().map(parse_ring).collect()).unwrap_or_default()
+}
+
+/// Timezone boundary polygons, e.g. from timezone-boundary-builder, indexed by bounding box
+#[derive(Default)]
+pub struct TzBoundaryIndex {
+  tree: RTree<ZonePolygon>,
+}
+
+pub type SharedTzIndex = Arc<TzBoundaryIndex>;
+
+impl TzBoundaryIndex {
+  pub fn empty() -> Self {
+    TzBoundaryIndex::default()
+  }
+
+  /// Loads the GeoJSON file named by TZ_BOUNDARIES_FILE, or an empty index if unset or unreadable
+  pub fn from_env() -> Self {
+    match get_tz_boundaries_file() {
+      Some(path) => match TzBoundaryIndex::load(&path) {
+        Ok(index) => {
+          tracing::info!("loaded {} timezone boundary polygons from {}", index.len(), path);
+          index
+        },
+        Err(message) => {
+          tracing::warn!("timezone boundaries unavailable: {}", message);
+          TzBoundaryIndex::empty()
+        }
+      },
+      None => TzBoundaryIndex::empty()
+    }
+  }
+
+  /// Reads a GeoJSON FeatureCollection of Polygon or MultiPolygon features with a tzid property
+  pub fn load(path: &str) -> Result<Self, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let data: Map<String, Value> = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+    let features = data.get("features").and_then(|f| f.as_array()).ok_or(format!("{} is not a FeatureCollection", path))?;
+    let mut polygons: Vec<ZonePolygon> = vec![];
+    for feature in features {
+      let properties = feature.get("properties");
+      let zone_opt = properties.and_then(|p| p.get("tzid").or(p.get("zoneName"))).and_then(|z| z.as_str());
+      let geometry = feature.get("geometry");
+      let geo_type = geometry.and_then(|g| g.get("type")).and_then(|t| t.as_str()).unwrap_or("");
+      let coordinates = geometry.and_then(|g| g.get("coordinates"));
+      if let (Some(zone_name), Some(coordinates)) = (zone_opt, coordinates) {
+        let zone_name: Arc<str> = Arc::from(zone_name);
+        let polygon_rings = match geo_type {
+          "Polygon" => vec![parse_polygon(coordinates)],
+          "MultiPolygon" => coordinates.as_array().map(|items| items.iter().map(parse_polygon).collect()).unwrap_or_default(),
+          _ => vec![]
+        };
+        polygons.extend(polygon_rings.into_iter().filter_map(|rings| ZonePolygon::new(zone_name.clone(), rings)));
+      }
+    }
+    if polygons.is_empty() {
+      return Err(format!("{} has no timezone polygons", path));
+    }
+    Ok(TzBoundaryIndex {
+      tree: RTree::bulk_load(polygons)
+    })
+  }
+
+  pub fn len(&self) -> usize {
+    self.tree.size()
+  }
+
+  pub fn is_loaded(&self) -> bool {
+    self.tree.size() > 0
+  }
+
+  pub fn zone_name(&self, geo: Geo) -> Option<String> {
+    let point = [geo.lng, geo.lat];
+    self.tree.locate_in_envelope_intersecting(&AABB::from_point(point))
+      .find(|polygon| polygon.contains(point))
+      .map(|polygon| polygon.zone_name.to_string())
+  }
+}
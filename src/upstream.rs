@@ -0,0 +1,285 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use rand::Rng;
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::{common::{get_breaker_cooldown_secs, get_breaker_threshold, get_upstream_concurrency, get_upstream_max_retries, get_upstream_timeout_secs}, errors::{GeoFinderError, GeoFinderResult}};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Upstream {
+  GeoTimeZone,
+  Astro,
+  GeoNames,
+  Addresses,
+}
+
+impl Upstream {
+  pub fn all() -> [Upstream; 4] {
+    [Self::GeoTimeZone, Self::Astro, Self::GeoNames, Self::Addresses]
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::GeoTimeZone => "GeoTimeZone API",
+      Self::Astro => "Astro API",
+      Self::GeoNames => "GeoNames",
+      Self::Addresses => "Addresses API",
+    }
+  }
+
+  /// Prefix for the per-service environment variables, e.g. GEONAMES_TIMEOUT_SECS
+  pub fn env_key(&self) -> &'static str {
+    match self {
+      Self::GeoTimeZone => "GEOTIMEZONE",
+      Self::Astro => "ASTRO",
+      Self::GeoNames => "GEONAMES",
+      Self::Addresses => "ADDRESSES",
+    }
+  }
+
+  fn default_timeout_secs(&self) -> u64 {
+    match self {
+      Self::GeoTimeZone => 5,
+      Self::Astro => 8,
+      Self::GeoNames => 6,
+      Self::Addresses => 10,
+    }
+  }
+
+  fn default_concurrency(&self) -> usize {
+    match self {
+      Self::GeoTimeZone => 16,
+      Self::Astro => 8,
+      Self::GeoNames => 8,
+      Self::Addresses => 4,
+    }
+  }
+}
+
+struct ServiceLimits {
+  timeout: Duration,
+  permits: Semaphore,
+  breaker: CircuitBreaker,
+}
+
+#[derive(Default)]
+struct BreakerState {
+  failures: u32,
+  opened_at: Option<Instant>,
+  probe_started: Option<Instant>,
+}
+
+/// Opens after `threshold` consecutive failures and fails fast until `cooldown` has passed.
+/// One trial request is then let through: success closes the breaker, failure reopens it.
+struct CircuitBreaker {
+  threshold: u32,
+  cooldown: Duration,
+  state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+  fn new(threshold: u32, cooldown: Duration) -> Self {
+    CircuitBreaker {
+      threshold,
+      cooldown,
+      state: Mutex::new(BreakerState::default())
+    }
+  }
+
+  fn allow(&self) -> bool {
+    let Ok(mut state) = self.state.lock() else {
+      return true;
+    };
+    match state.opened_at {
+      None => true,
+      Some(opened_at) if opened_at.elapsed() >= self.cooldown => {
+        // a trial whose caller was dropped mid-request must not hold the breaker open forever
+        let probing = state.probe_started.map(|started| started.elapsed() < self.cooldown).unwrap_or(false);
+        if !probing {
+          state.probe_started = Some(Instant::now());
+        }
+        !probing
+      },
+      _ => false
+    }
+  }
+
+  fn record_success(&self) {
+    if let Ok(mut state) = self.state.lock() {
+      *state = BreakerState::default();
+    }
+  }
+
+  fn record_failure(&self) {
+    if let Ok(mut state) = self.state.lock() {
+      state.failures += 1;
+      state.probe_started = None;
+      if state.failures >= self.threshold {
+        state.opened_at = Some(Instant::now());
+      }
+    }
+  }
+
+  fn status(&self) -> (bool, u32) {
+    self.state.lock().map(|state| (state.opened_at.is_some(), state.failures)).unwrap_or((false, 0))
+  }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct BreakerStatus {
+  pub service: &'static str,
+  pub open: bool,
+  pub failures: u32,
+}
+
+/// One pooled HTTP client shared by all upstream services.
+/// Each service has its own request timeout and concurrency limit, and idempotent GETs
+/// are retried with jittered exponential backoff on timeouts, connection failures and 5xx/429 responses.
+/// A circuit breaker per service fails fast with `UpstreamUnavailable` while the service is down.
+pub struct UpstreamClient {
+  http: reqwest::Client,
+  max_retries: u32,
+  services: HashMap<Upstream, ServiceLimits>,
+}
+
+pub type SharedUpstream = Arc<UpstreamClient>;
+
+const BACKOFF_BASE_MS: u64 = 200;
+
+impl UpstreamClient {
+  pub fn new() -> Self {
+    let http = reqwest::Client::builder()
+      .connect_timeout(Duration::from_secs(3))
+      .pool_idle_timeout(Duration::from_secs(90))
+      .build()
+      .unwrap_or_default();
+    let services = Upstream::all().into_iter().map(|service| {
+      let limits = ServiceLimits {
+        timeout: Duration::from_secs(get_upstream_timeout_secs(service.env_key(), service.default_timeout_secs())),
+        permits: Semaphore::new(get_upstream_concurrency(service.env_key(), service.default_concurrency())),
+        breaker: CircuitBreaker::new(get_breaker_threshold(), Duration::from_secs(get_breaker_cooldown_secs())),
+      };
+      (service, limits)
+    }).collect::<HashMap<Upstream, ServiceLimits>>();
+    UpstreamClient {
+      http,
+      max_retries: get_upstream_max_retries(),
+      services
+    }
+  }
+
+  /// Starts a request with the service's timeout applied
+  pub fn request(&self, service: Upstream, method: Method, uri: &str) -> RequestBuilder {
+    let builder = self.http.request(method, uri);
+    match self.services.get(&service) {
+      Some(limits) => builder.timeout(limits.timeout),
+      None => builder
+    }
+  }
+
+  pub fn breaker_statuses(&self) -> Vec<BreakerStatus> {
+    Upstream::all().into_iter().filter_map(|service| {
+      self.services.get(&service).map(|limits| {
+        let (open, failures) = limits.breaker.status();
+        BreakerStatus { service: service.name(), open, failures }
+      })
+    }).collect()
+  }
+
+  pub fn get(&self, service: Upstream, uri: &str) -> RequestBuilder {
+    self.request(service, Method::GET, uri)
+  }
+
+  pub fn post(&self, service: Upstream, uri: &str) -> RequestBuilder {
+    self.request(service, Method::POST, uri)
+  }
+
+  /// Sends the request and decodes the JSON body, mapping transport, status and
+  /// decoding failures to the matching upstream error
+  pub async fn send_json<T: DeserializeOwned>(&self, request: RequestBuilder, service: Upstream) -> GeoFinderResult<T> {
+    self.send_json_validated(request, service, |_| Ok(())).await
+  }
+
+  /// As `send_json`, with a check for errors reported inside a successful response such as
+  /// GeoNames quota errors. The circuit breaker records success only once the body passes the check,
+  /// and upstream errors returned by the check count as failures.
+  pub async fn send_json_validated<T, F>(&self, request: RequestBuilder, service: Upstream, validate: F) -> GeoFinderResult<T>
+  where
+    T: DeserializeOwned,
+    F: FnOnce(&T) -> GeoFinderResult<()>
+  {
+    let response = self.send(request, service).await?;
+    let result = response.json::<T>()
+      .await
+      .map_err(|e| GeoFinderError::from_upstream(service.name(), e))
+      .and_then(|data| validate(&data).map(|_| data));
+    if let Some(limits) = self.services.get(&service) {
+      match &result {
+        Err(error) if error.is_upstream() => limits.breaker.record_failure(),
+        _ => limits.breaker.record_success()
+      }
+    }
+    result
+  }
+
+  async fn send(&self, request: RequestBuilder, service: Upstream) -> GeoFinderResult<Response> {
+    let limits = self.services.get(&service);
+    if let Some(limits) = limits {
+      if !limits.breaker.allow() {
+        return Err(GeoFinderError::UpstreamUnavailable(service.name().to_string()));
+      }
+    }
+    let request = request.build().map_err(|e| GeoFinderError::from_upstream(service.name(), e))?;
+    let max_retries = if request.method() == Method::GET { self.max_retries } else { 0 };
+    let mut attempt = 0;
+    let mut next = Some(request);
+    while let Some(current) = next.take() {
+      // keep a copy for the next attempt before the request is consumed
+      if attempt < max_retries {
+        next = current.try_clone();
+      }
+      let result = {
+        let _permit = match limits {
+          Some(limits) => limits.permits.acquire().await.ok(),
+          None => None
+        };
+        self.http.execute(current).await
+      };
+      let retryable = match &result {
+        Ok(resp) => is_retryable_status(resp.status()),
+        Err(e) => e.is_timeout() || e.is_connect()
+      };
+      if !retryable || next.is_none() {
+        if let Some(limits) = limits {
+          // client errors still show the service is answering, while successful
+          // responses are recorded by the caller once the body has been checked
+          let succeeded = result.as_ref().map(|resp| resp.status().is_success()).unwrap_or(false);
+          if retryable {
+            limits.breaker.record_failure();
+          } else if !succeeded {
+            limits.breaker.record_success();
+          }
+        }
+        return result
+          .and_then(|resp| resp.error_for_status())
+          .map_err(|e| GeoFinderError::from_upstream(service.name(), e));
+      }
+      tracing::debug!("retrying {} after attempt {}", service.name(), attempt + 1);
+      tokio::time::sleep(backoff_delay(attempt)).await;
+      attempt += 1;
+    }
+    Err(GeoFinderError::UpstreamUnavailable(service.name().to_string()))
+  }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+  status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Exponential backoff with up to 50% random jitter so concurrent retries spread out
+fn backoff_delay(attempt: u32) -> Duration {
+  let base = BACKOFF_BASE_MS * 2u64.pow(attempt.min(6));
+  let jitter = rand::thread_rng().gen_range(0..=base / 2);
+  Duration::from_millis(base + jitter)
+}